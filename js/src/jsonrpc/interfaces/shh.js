@@ -69,13 +69,17 @@ export default {
           },
           ttl: {
             type: Quantity, desc: 'Integer of the time to live in seconds.'
+          },
+          sequence: {
+            type: Quantity, desc: 'Sequence number of the message. Receivers drop signed messages whose sequence number is not above the last one seen for the same topic and sender.',
+            optional: true
           }
         }
       }
     ],
     returns: {
-      type: Boolean,
-      desc: '`true` if the message was send, otherwise `false`'
+      type: Data,
+      desc: '32 Bytes - The ID of the envelope sent'
     }
   },
 
@@ -248,6 +252,10 @@ export default {
         padding: {
           type: Data,
           desc: 'Optional padding which was decoded.'
+        },
+        sequence: {
+          type: Quantity,
+          desc: 'Sequence number attached by the sender, if any.'
         }
       }
     }
@@ -835,6 +835,18 @@ usage! {
 			"--whisper-pool-size=[MB]",
 			"Target size of the whisper message pool in megabytes.",
 
+			FLAG flag_whisper_advertise_topics: (bool) = false, or |c: &Config| otry!(c.whisper).advertise_topics,
+			"--whisper-advertise-topics",
+			"Send peers a topic filter built from our own filters, so they only relay matching messages. Reveals which topics we listen for; see --whisper-decoy-topics.",
+
+			ARG arg_whisper_decoy_topics: (usize) = 0usize, or |c: &Config| otry!(c.whisper).decoy_topics.clone(),
+			"--whisper-decoy-topics=[NUM]",
+			"Number of random topics mixed into the advertised topic filter, to hide which topics we listen for at the cost of extra inbound traffic.",
+
+			ARG arg_whisper_padding_block: (usize) = 0usize, or |c: &Config| otry!(c.whisper).padding_block.clone(),
+			"--whisper-padding-block=[BYTES]",
+			"Pad the payloads of messages posted over RPC to a multiple of this many bytes, so their size reveals less about their content. 0 disables padding.",
+
 		["Legacy options"]
 			FLAG flag_dapps_apis_all: (bool) = false, or |_| None,
 			"--dapps-apis-all",
@@ -1191,6 +1203,9 @@ struct Misc {
 struct Whisper {
 	enabled: Option<bool>,
 	pool_size: Option<usize>,
+	advertise_topics: Option<bool>,
+	decoy_topics: Option<usize>,
+	padding_block: Option<usize>,
 }
 
 #[cfg(test)]
@@ -1535,6 +1550,9 @@ mod tests {
 			// -- Whisper options.
 			flag_whisper: false,
 			arg_whisper_pool_size: 20,
+			flag_whisper_advertise_topics: false,
+			arg_whisper_decoy_topics: 0,
+			arg_whisper_padding_block: 0,
 
 			// -- Legacy Options
 			flag_geth: false,
@@ -1767,6 +1785,9 @@ mod tests {
 			whisper: Some(Whisper {
 				enabled: Some(true),
 				pool_size: Some(50),
+				advertise_topics: None,
+				decoy_topics: None,
+				padding_block: None,
 			}),
 			stratum: None,
 		});
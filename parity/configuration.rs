@@ -1106,6 +1106,9 @@ impl Configuration {
 		::whisper::Config {
 			enabled: self.args.flag_whisper,
 			target_message_pool_size: self.args.arg_whisper_pool_size * 1024 * 1024,
+			advertise_topics: self.args.flag_whisper_advertise_topics,
+			decoy_topics: self.args.arg_whisper_decoy_topics,
+			padding_block: self.args.arg_whisper_padding_block,
 		}
 	}
 }
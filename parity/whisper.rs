@@ -17,6 +17,7 @@
 use std::sync::Arc;
 use std::io;
 
+use bigint::hash::H256;
 use ethsync::{AttachedProtocol, ManageNetwork};
 use parity_rpc::Metadata;
 use parity_whisper::message::Message;
@@ -28,6 +29,9 @@ use parity_whisper::rpc::{WhisperClient, PoolHandle, FilterManager};
 pub struct Config {
 	pub enabled: bool,
 	pub target_message_pool_size: usize,
+	pub advertise_topics: bool,
+	pub decoy_topics: usize,
+	pub padding_block: usize,
 }
 
 impl Default for Config {
@@ -35,6 +39,9 @@ impl Default for Config {
 		Config {
 			enabled: false,
 			target_message_pool_size: 10 * 1024 * 1024,
+			advertise_topics: false,
+			decoy_topics: 0,
+			padding_block: 0,
 		}
 	}
 }
@@ -48,8 +55,8 @@ pub struct NetPoolHandle {
 }
 
 impl PoolHandle for NetPoolHandle {
-	fn relay(&self, message: Message) -> bool {
-		let mut res = false;
+	fn relay(&self, message: Message) -> Option<H256> {
+		let mut res = None;
 		let mut message = Some(message);
 		self.net.with_proto_context(whisper_net::PROTOCOL_ID, &mut move |ctx| {
 			if let Some(message) = message.take() {
@@ -68,24 +75,34 @@ impl PoolHandle for NetPoolHandle {
 pub struct RpcFactory {
 	net: Arc<WhisperNetwork<Arc<FilterManager>>>,
 	manager: Arc<FilterManager>,
+	padding_block: usize,
 }
 
 impl RpcFactory {
 	pub fn make_handler(&self, net: Arc<ManageNetwork>) -> WhisperClient<NetPoolHandle, Metadata> {
 		let handle = NetPoolHandle { handle: self.net.clone(), net: net };
-		WhisperClient::new(handle, self.manager.clone())
+		WhisperClient::new(handle, self.manager.clone()).with_padding_block(self.padding_block)
 	}
 }
 
 /// Sets up whisper protocol and RPC handler.
 ///
-/// Will target the given pool size.
+/// Will target the configured pool size. Topic blooms are only advertised to
+/// peers when enabled, with the configured number of decoy topics mixed in.
 #[cfg(not(feature = "ipc"))]
-pub fn setup(target_pool_size: usize, protos: &mut Vec<AttachedProtocol>)
+pub fn setup(config: &Config, protos: &mut Vec<AttachedProtocol>)
 	-> io::Result<Option<RpcFactory>>
 {
-	let manager = Arc::new(FilterManager::new()?);
-	let net = Arc::new(WhisperNetwork::new(target_pool_size, manager.clone()));
+	let manager = Arc::new(FilterManager::with_decoy_topics(config.decoy_topics)?);
+	let net = Arc::new(WhisperNetwork::new(config.target_message_pool_size, manager.clone()));
+
+	// advertise our filters' topics to peers as they change.
+	if config.advertise_topics {
+		let weak_net = Arc::downgrade(&net);
+		manager.set_bloom_listener(move |bloom| if let Some(net) = weak_net.upgrade() {
+			net.set_topic_filter(bloom);
+		});
+	}
 
 	protos.push(AttachedProtocol {
 		handler: net.clone() as Arc<_>,
@@ -102,14 +119,14 @@ pub fn setup(target_pool_size: usize, protos: &mut Vec<AttachedProtocol>)
 		protocol_id: whisper_net::PARITY_PROTOCOL_ID,
 	});
 
-	let factory = RpcFactory { net: net, manager: manager };
+	let factory = RpcFactory { net: net, manager: manager, padding_block: config.padding_block };
 
 	Ok(Some(factory))
 }
 
 // TODO: make it possible to attach generic protocols in IPC.
 #[cfg(feature = "ipc")]
-pub fn setup(_target_pool_size: usize, _protos: &mut Vec<AttachedProtocol>)
+pub fn setup(_config: &Config, _protos: &mut Vec<AttachedProtocol>)
 	-> io::Result<Option<RpcFactory>>
 {
 	Ok(None)
@@ -32,7 +32,25 @@ pub fn log2(x: usize) -> u32 {
 	mem::size_of::<usize>() as u32 * 8 - n
 }
 
+/// Whether every one of `topics` may be present in a log bloom.
+///
+/// `topics` are the bloomed values themselves, i.e. the Keccak hashes of log
+/// topics or addresses. True for an empty list.
+pub fn matches_topics(bloom: &H2048, topics: &[H256]) -> bool {
+	topics.iter().all(|topic| bloom.contains_bloomed(topic))
+}
+
+/// Number of bits set in a bloom for each bloomed item, as in the yellow paper.
+pub const BITS_PER_ITEM: usize = 3;
+
 /// Bloom operations.
+///
+/// Blooms are implemented uniformly for hashes of every width which is a
+/// power of two bytes. Bloomed items set up to `BITS_PER_ITEM` bits. Each
+/// bit index is read from consecutive bytes of the item, taking as many
+/// bytes as needed to address every bit in the bloom: one byte for `H64`,
+/// two for `H256`, `H512` and `H2048`. The item must therefore be at least
+/// `BITS_PER_ITEM` times that long.
 pub trait Bloomable: Sized + Default + DerefMut<Target = [u8]> {
 	/// When interpreting self as a bloom output, augment (bit-wise OR) with the a bloomed version of `b`.
 	fn shift_bloomed<'a, T>(&'a mut self, b: &T) -> &'a mut Self where T: Bloomable;
@@ -51,6 +69,33 @@ pub trait Bloomable: Sized + Default + DerefMut<Target = [u8]> {
 
 	/// Check to see whether this hash, interpreted as a bloom, contains the value `b` when bloomed.
 	fn contains_bloomed<T>(&self, b: &T) -> bool where T: Bloomable;
+
+	/// Whether every bit set in `self` is also set in `other`.
+	fn is_subset_of(&self, other: &Self) -> bool {
+		self.iter().zip(other.iter()).all(|(a, b)| a & b == *a)
+	}
+
+	/// Reset every bit, leaving an empty bloom.
+	fn clear(&mut self) {
+		for byte in self.iter_mut() {
+			*byte = 0;
+		}
+	}
+
+	/// Bits of `self` which are not set in `other` (`self & !other`).
+	///
+	/// This is lossy: bits shared with items which are not part of `other`
+	/// are cleared too, so removing a contribution may also remove items which
+	/// were bloomed into `self` separately. Blooms should be rebuilt from
+	/// their items where exactness matters.
+	fn without(&self, other: &Self) -> Self {
+		let mut ret = Self::default();
+		for (r, (a, b)) in ret.iter_mut().zip(self.iter().zip(other.iter())) {
+			*r = a & !b;
+		}
+
+		ret
+	}
 }
 
 macro_rules! impl_bloomable_for_hash {
@@ -65,13 +110,11 @@ macro_rules! impl_bloomable_for_hash {
 			}
 
 			fn bloom_part<T>(&self, m: usize) -> T where T: Bloomable + Default {
-				// numbers of bits
-				// TODO: move it to some constant
-				let p = 3;
+				let p = BITS_PER_ITEM;
 
 				let bloom_bits = m * 8;
 				let mask = bloom_bits - 1;
-				let bloom_bytes = (log2(bloom_bits) + 7) / 8;
+				let bloom_bytes = ((log2(bloom_bits) + 7) / 8) as usize;
 
 				// must be a power of 2
 				assert_eq!(m & (m - 1), 0);
@@ -2,8 +2,8 @@ extern crate hash;
 extern crate ethcore_bigint;
 extern crate bloomable;
 
-use ethcore_bigint::hash::{H160, H256, H2048};
-use bloomable::Bloomable;
+use ethcore_bigint::hash::{H160, H256, H512, H2048};
+use bloomable::{Bloomable, BITS_PER_ITEM, matches_topics};
 use hash::keccak;
 
 #[test]
@@ -25,3 +25,91 @@ fn shift_bloomed() {
 	assert!(my_bloom.contains_bloomed(&keccak(&address)));
 	assert!(my_bloom.contains_bloomed(&keccak(&topic)));
 }
+
+#[test]
+fn matches_all_topics() {
+	let bloom: H2048 = "00000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000002020000000000000000000000000000000000000000000008000000001000000000000000000000000000000000000000000000000000001000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000".into();
+	let address: H160 = "ef2d6d194084c2de36e0dabfce45d046b37d1106".into();
+	let topic: H256 = "02c69be41d0b7e40352fc85be1cd65eb03d40ef8427a0ca4596b1ead9a00e9fc".into();
+	let absent: H256 = "4c9c0d7bd4b3ecd0f5e0d2c5c6e9f3c5a1f1a1b8e3f1c7f0f8d9a6b2c3d4e5f6".into();
+
+	assert!(matches_topics(&bloom, &[]));
+	assert!(matches_topics(&bloom, &[keccak(&topic)]));
+	assert!(matches_topics(&bloom, &[keccak(&address), keccak(&topic)]));
+
+	assert!(!matches_topics(&bloom, &[keccak(&absent)]));
+	assert!(!matches_topics(&bloom, &[keccak(&address), keccak(&absent), keccak(&topic)]));
+}
+
+fn contains_inserted<B: Bloomable>() {
+	let mut aggregate = B::default();
+
+	for i in 0..512u32 {
+		let item = keccak(&[i as u8, (i >> 8) as u8]);
+		let bloom = B::from_bloomed(&item);
+
+		let bits_set = bloom.iter().map(|b| b.count_ones() as usize).sum::<usize>();
+		assert!(bits_set >= 1 && bits_set <= BITS_PER_ITEM);
+		assert!(bloom.contains_bloomed(&item));
+
+		aggregate.shift_bloomed(&item);
+		assert!(aggregate.contains_bloomed(&item));
+	}
+
+	for i in 0..512u32 {
+		assert!(aggregate.contains_bloomed(&keccak(&[i as u8, (i >> 8) as u8])));
+	}
+}
+
+#[test]
+fn contains_inserted_h256() {
+	contains_inserted::<H256>();
+}
+
+#[test]
+fn contains_inserted_h512() {
+	contains_inserted::<H512>();
+}
+
+#[test]
+fn contains_inserted_h2048() {
+	contains_inserted::<H2048>();
+}
+
+#[test]
+fn clear() {
+	let mut bloom = H2048::from_bloomed(&keccak(&[1u8]));
+	assert!(bloom != H2048::default());
+
+	bloom.clear();
+	assert_eq!(bloom, H2048::default());
+}
+
+#[test]
+fn without() {
+	let (x, y) = (keccak(&[1u8]), keccak(&[2u8]));
+	let (x_bloom, y_bloom) = (H2048::from_bloomed(&x), H2048::from_bloomed(&y));
+
+	// the items share no bits, so removal is exact.
+	assert!((&x_bloom & &y_bloom).is_zero());
+
+	let both = H2048::default().with_bloomed(&x).with_bloomed(&y);
+	let without_y = both.without(&y_bloom);
+
+	assert!(without_y.contains_bloomed(&x));
+	assert!(!without_y.contains_bloomed(&y));
+	assert_eq!(without_y, x_bloom);
+	assert_eq!(both.without(&both), H2048::default());
+}
+
+#[test]
+fn is_subset_of() {
+	let (x, y) = (keccak(&[1u8]), keccak(&[2u8]));
+	let x_bloom = H2048::from_bloomed(&x);
+	let both = x_bloom.with_bloomed(&y);
+
+	assert!(x_bloom.is_subset_of(&both));
+	assert!(!both.is_subset_of(&x_bloom));
+	assert!(H2048::default().is_subset_of(&x_bloom));
+	assert!(both.is_subset_of(&both));
+}
@@ -0,0 +1,76 @@
+// Copyright 2015-2017 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Time sources for the whisper network.
+//!
+//! Expiry and pruning consult a `Clock` rather than the system time directly,
+//! so tests can control exactly when messages expire.
+
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+	/// The current time.
+	fn now(&self) -> SystemTime;
+}
+
+/// The system's wall clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+	fn now(&self) -> SystemTime {
+		SystemTime::now()
+	}
+}
+
+/// A clock which only moves when told to.
+#[derive(Debug)]
+pub struct MockClock {
+	now: Mutex<SystemTime>,
+}
+
+impl MockClock {
+	/// Create a clock stopped at the given time.
+	pub fn new(now: SystemTime) -> Self {
+		MockClock { now: Mutex::new(now) }
+	}
+
+	/// Set the current time.
+	pub fn set(&self, now: SystemTime) {
+		*self.now.lock() = now;
+	}
+
+	/// Move the clock forward.
+	pub fn advance(&self, by: Duration) {
+		*self.now.lock() += by;
+	}
+}
+
+impl Clock for MockClock {
+	fn now(&self) -> SystemTime {
+		*self.now.lock()
+	}
+}
+
+// lets a clock be shared with the network, e.g. to advance it from tests.
+impl<C: Clock + ?Sized> Clock for ::std::sync::Arc<C> {
+	fn now(&self) -> SystemTime {
+		(**self).now()
+	}
+}
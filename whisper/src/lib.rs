@@ -53,8 +53,9 @@ extern crate serde_derive;
 extern crate serde_json;
 
 pub use self::message::Message;
-pub use self::net::{Network, MessageHandler};
+pub use self::net::{AcceptError, Network, MessageHandler, PowTarget, WhisperRole};
 
+pub mod clock;
 pub mod message;
 pub mod net;
 pub mod rpc;
@@ -17,6 +17,7 @@
 //! Whisper message parsing, handlers, and construction.
 
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::time::{self, SystemTime, Duration};
 
 use bigint::hash::{H256, H512};
@@ -109,11 +110,15 @@ pub fn bloom_topics(topics: &[Topic]) -> H512 {
 	bloom
 }
 
+/// Maximum number of topics a message may carry.
+pub const MAX_TOPICS: usize = 16;
+
 /// Message errors.
 #[derive(Debug)]
 pub enum Error {
 	Decoder(DecoderError),
 	EmptyTopics,
+	InvalidTopics,
 	LivesTooLong,
 	IssuedInFuture,
 	ZeroTTL,
@@ -133,10 +138,26 @@ impl fmt::Display for Error {
 			Error::IssuedInFuture => write!(f, "Message issued in future."),
 			Error::ZeroTTL => write!(f, "Message live for zero time."),
 			Error::EmptyTopics => write!(f, "Message has no topics."),
+			Error::InvalidTopics => write!(f, "Message has duplicate topics or more than {}.", MAX_TOPICS),
 		}
 	}
 }
 
+// check that a topic list is non-empty, within bounds, and free of duplicates.
+fn check_topics(topics: &[Topic]) -> Result<(), Error> {
+	use std::collections::HashSet;
+
+	if topics.is_empty() { return Err(Error::EmptyTopics) }
+	if topics.len() > MAX_TOPICS { return Err(Error::InvalidTopics) }
+
+	let mut seen = HashSet::with_capacity(topics.len());
+	if topics.iter().all(|topic| seen.insert(topic)) {
+		Ok(())
+	} else {
+		Err(Error::InvalidTopics)
+	}
+}
+
 fn append_topics<'a>(s: &'a mut RlpStream, topics: &[Topic]) -> &'a mut RlpStream {
 	if topics.len() == 1 {
 		s.append(&topics[0])
@@ -153,6 +174,11 @@ fn decode_topics(rlp: UntrustedRlp) -> Result<SmallVec<[Topic; 4]>, DecoderError
 	}
 }
 
+// identifier of an envelope given its RLP encoding.
+pub(crate) fn envelope_id(encoded: &[u8]) -> H256 {
+	H256(keccak256(encoded))
+}
+
 // Raw envelope struct.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Envelope {
@@ -169,6 +195,19 @@ pub struct Envelope {
 }
 
 impl Envelope {
+	/// Deterministic identifier: the Keccak-256 hash of the RLP encoding.
+	///
+	/// This is the same as the hash of the corresponding `Message`.
+	pub fn id(&self) -> H256 {
+		envelope_id(&::rlp::encode(self))
+	}
+
+	/// Time left until expiry at `now`. Zero once expired.
+	pub fn remaining_ttl(&self, now: SystemTime) -> Duration {
+		let expiry = time::UNIX_EPOCH + Duration::from_secs(self.expiry);
+		expiry.duration_since(now).unwrap_or(Duration::from_secs(0))
+	}
+
 	/// Whether the message is multi-topic. Only relay these to Parity peers.
 	pub fn is_multitopic(&self) -> bool {
 		self.topics.len() != 1
@@ -199,6 +238,14 @@ impl Envelope {
 	}
 }
 
+// hashes the canonical RLP encoding, which is equal exactly when the
+// envelopes are.
+impl Hash for Envelope {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		state.write(&::rlp::encode(self));
+	}
+}
+
 impl rlp::Encodable for Envelope {
 	fn rlp_append(&self, s: &mut RlpStream) {
 		s.begin_list(5)
@@ -225,10 +272,6 @@ impl rlp::Decodable for Envelope {
 	}
 }
 
-/// Error indicating no topics.
-#[derive(Debug, Copy, Clone)]
-pub struct EmptyTopics;
-
 /// Message creation parameters.
 /// Pass this to `Message::create` to make a message.
 pub struct CreateParams {
@@ -236,7 +279,8 @@ pub struct CreateParams {
 	pub ttl: u64,
 	/// payload data.
 	pub payload: Vec<u8>,
-	/// Topics. May not be empty.
+	/// Topics. May not be empty, contain duplicates, or number more than
+	/// `MAX_TOPICS`.
 	pub topics: Vec<Topic>,
 	/// How many milliseconds to spend proving work.
 	pub work: u64,
@@ -253,12 +297,12 @@ pub struct Message {
 
 impl Message {
 	/// Create a message from creation parameters.
-	/// Panics if TTL is 0.
-	pub fn create(params: CreateParams) -> Result<Self, EmptyTopics> {
+	/// Fails if the topics are invalid. Panics if TTL is 0.
+	pub fn create(params: CreateParams) -> Result<Self, Error> {
 		use byteorder::{BigEndian, ByteOrder};
 		use rand::{Rng, SeedableRng, XorShiftRng};
 
-		if params.topics.is_empty() { return Err(EmptyTopics) }
+		check_topics(&params.topics)?;
 
 		let mut rng = {
 			let mut thread_rng = ::rand::thread_rng();
@@ -326,7 +370,7 @@ impl Message {
 		Ok(Message::from_components(
 			envelope,
 			encoded.len(),
-			H256(keccak256(&encoded)),
+			envelope_id(&encoded),
 			SystemTime::now(),
 		).expect("Message generated here known to be valid; qed"))
 	}
@@ -335,7 +379,7 @@ impl Message {
 	pub fn decode(rlp: UntrustedRlp, now: SystemTime) -> Result<Self, Error> {
 		let envelope: Envelope = rlp.as_val()?;
 		let encoded_size = rlp.as_raw().len();
-		let hash = H256(keccak256(rlp.as_raw()));
+		let hash = envelope_id(rlp.as_raw());
 
 		Message::from_components(envelope, encoded_size, hash, now)
 	}
@@ -350,6 +394,8 @@ impl Message {
 		if envelope.expiry <= envelope.ttl { return Err(Error::LivesTooLong) }
 		if envelope.ttl == 0 { return Err(Error::ZeroTTL) }
 
+		// duplicate or excess topics aren't fatal here: older nodes relay
+		// such messages, so the pool drops them via `check_topics` instead.
 		if envelope.topics.is_empty() { return Err(Error::EmptyTopics) }
 
 		let issue_time_adjusted = Duration::from_secs(
@@ -391,6 +437,13 @@ impl Message {
 		&self.bloom
 	}
 
+	/// Check that the topics are free of duplicates and number at most
+	/// `MAX_TOPICS`, as required of locally created messages. Decoding
+	/// doesn't enforce this.
+	pub fn check_topics(&self) -> Result<(), Error> {
+		check_topics(&self.envelope.topics)
+	}
+
 	/// Get the work proved by the hash.
 	pub fn work_proved(&self) -> f64 {
 		let proving_hash = self.envelope.proving_hash();
@@ -398,6 +451,18 @@ impl Message {
 		work_factor_proved(self.encoded_size as _, self.envelope.ttl, proving_hash)
 	}
 
+	/// Priority of the message for retention and relay: the work proved,
+	/// scaled by the fraction of the time-to-live remaining at `now`.
+	/// Expired messages have zero priority.
+	pub fn priority(&self, now: SystemTime) -> f64 {
+		let remaining = self.envelope.remaining_ttl(now);
+		let remaining = remaining.as_secs() as f64 + remaining.subsec_nanos() as f64 / 1_000_000_000f64;
+
+		let ttl_fraction = (remaining / self.envelope.ttl as f64).min(1f64);
+
+		self.work_proved() * ttl_fraction
+	}
+
 	/// Get the expiry time.
 	pub fn expiry(&self) -> SystemTime {
 		time::UNIX_EPOCH + Duration::from_secs(self.envelope.expiry)
@@ -435,6 +500,46 @@ mod tests {
 		}).is_ok());
 	}
 
+	#[test]
+	fn rejects_invalid_topics() {
+		let create = |topics| Message::create(CreateParams {
+			ttl: 100,
+			payload: vec![1, 2, 3, 4],
+			topics: topics,
+			work: 0,
+		});
+
+		match create(vec![]) { Err(Error::EmptyTopics) => {}, other => panic!("unexpected {:?}", other) }
+		match create(vec![Topic([1, 2, 1, 2]), Topic([3, 4, 3, 4]), Topic([1, 2, 1, 2])]) {
+			Err(Error::InvalidTopics) => {},
+			other => panic!("unexpected {:?}", other),
+		}
+		match create((0..MAX_TOPICS as u8 + 1).map(|i| Topic([i, 0, 0, 0])).collect()) {
+			Err(Error::InvalidTopics) => {},
+			other => panic!("unexpected {:?}", other),
+		}
+
+		assert!(create((0..MAX_TOPICS as u8).map(|i| Topic([i, 0, 0, 0])).collect()).is_ok());
+	}
+
+	#[test]
+	fn decode_allows_duplicate_topics() {
+		let envelope = Envelope {
+			expiry: 100_000,
+			ttl: 30,
+			data: vec![9; 256],
+			topics: SmallVec::from_slice(&[Topic([1, 2, 3, 4]), Topic([1, 2, 3, 4])]),
+			nonce: 1010101,
+		};
+
+		let encoded = ::rlp::encode(&envelope);
+		let message = Message::decode(UntrustedRlp::new(&*encoded), unix_time(100_000)).unwrap();
+		match message.check_topics() {
+			Err(Error::InvalidTopics) => {},
+			other => panic!("unexpected {:?}", other),
+		}
+	}
+
 	#[test]
 	fn round_trip() {
 		let envelope = Envelope {
@@ -467,6 +572,114 @@ mod tests {
 		assert_eq!(envelope, decoded)
 	}
 
+	#[test]
+	fn round_trip_random() {
+		use rand::{Rng, SeedableRng, XorShiftRng};
+
+		let mut rng = XorShiftRng::from_seed([0x5eed, 0xc0ffee, 0xdecade, 0xf00d]);
+
+		for i in 0..500 {
+			// cover empty data and zero, one, and many topics explicitly.
+			let data_len = if i % 10 == 0 { 0 } else { rng.gen_range(0, 2048) };
+			let topic_count = i % 5;
+
+			let envelope = Envelope {
+				expiry: rng.gen(),
+				ttl: rng.gen(),
+				topics: (0..topic_count).map(|_| Topic(rng.gen())).collect(),
+				data: rng.gen_iter().take(data_len).collect(),
+				nonce: rng.gen(),
+			};
+
+			let encoded = ::rlp::encode(&envelope);
+			let decoded: Envelope = ::rlp::decode(&encoded);
+
+			assert_eq!(envelope, decoded, "round trip failed for case {}", i);
+			assert_eq!(&*encoded, &*::rlp::encode(&decoded));
+		}
+	}
+
+	#[test]
+	fn priority_prefers_fresh_messages() {
+		let now = unix_time(100_000);
+		let message = |expiry, nonce| {
+			let envelope = Envelope {
+				expiry: expiry,
+				ttl: 1000,
+				data: vec![9; 256],
+				topics: SmallVec::from_slice(&[Default::default()]),
+				nonce: nonce,
+			};
+
+			let encoded = ::rlp::encode(&envelope);
+			Message::decode(UntrustedRlp::new(&*encoded), now).unwrap()
+		};
+
+		// fresh, with whatever work the first nonce happens to prove.
+		let fresh = message(101_000, 0);
+
+		// a second away from expiry, but with more work proved.
+		let nearly_expired = (1..).map(|nonce| message(100_001, nonce))
+			.find(|m| m.work_proved() > fresh.work_proved())
+			.unwrap();
+
+		assert!(nearly_expired.priority(now) < fresh.priority(now));
+		assert_eq!(fresh.priority(unix_time(101_000)), 0f64);
+	}
+
+	#[test]
+	fn stable_id() {
+		let envelope = Envelope {
+			expiry: 100_000,
+			ttl: 30,
+			data: vec![9; 256],
+			topics: SmallVec::from_slice(&[Default::default(), Topic([1, 2, 3, 4])]),
+			nonce: 1010101,
+		};
+
+		let encoded = ::rlp::encode(&envelope);
+		let decoded: Envelope = ::rlp::decode(&encoded);
+		assert_eq!(envelope.id(), decoded.id());
+
+		let message = Message::decode(UntrustedRlp::new(&*encoded), unix_time(100_000)).unwrap();
+		assert_eq!(message.hash(), &envelope.id());
+	}
+
+	#[test]
+	fn remaining_ttl() {
+		let envelope = Envelope {
+			expiry: 100_000,
+			ttl: 30,
+			data: vec![9; 256],
+			topics: SmallVec::from_slice(&[Default::default()]),
+			nonce: 1010101,
+		};
+
+		assert_eq!(envelope.remaining_ttl(unix_time(99_980)), Duration::from_secs(20));
+		assert_eq!(envelope.remaining_ttl(unix_time(100_000)), Duration::from_secs(0));
+		assert_eq!(envelope.remaining_ttl(unix_time(100_500)), Duration::from_secs(0));
+	}
+
+	#[test]
+	fn envelope_hash_set() {
+		use std::collections::HashSet;
+
+		let envelope = Envelope {
+			expiry: 100_000,
+			ttl: 30,
+			data: vec![9; 256],
+			topics: SmallVec::from_slice(&[Default::default()]),
+			nonce: 1010101,
+		};
+
+		let mut other = envelope.clone();
+		other.nonce += 1;
+
+		let set: HashSet<_> = vec![envelope.clone(), envelope.clone(), other].into_iter().collect();
+		assert_eq!(set.len(), 2);
+		assert!(set.contains(&envelope));
+	}
+
 	#[test]
 	fn passes_checks() {
 		let envelope = Envelope {
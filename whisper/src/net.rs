@@ -16,11 +16,12 @@
 
 //! Whisper messaging system as a DevP2P subprotocol.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::cmp::Ordering;
 use std::fmt;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
 
 use bigint::hash::{H256, H512};
 use network::{HostInfo, NetworkContext, NetworkError, NodeId, PeerId, ProtocolId, TimerToken};
@@ -28,7 +29,8 @@ use ordered_float::OrderedFloat;
 use parking_lot::{Mutex, RwLock};
 use rlp::{DecoderError, RlpStream, UntrustedRlp};
 
-use message::{Message, Error as MessageError};
+use clock::{Clock, SystemClock};
+use message::{envelope_id, Envelope, Message, Error as MessageError};
 
 // how often periodic relays are. when messages are imported
 // we directly broadcast.
@@ -44,6 +46,10 @@ pub const SUPPORTED_VERSIONS: &'static [u8] = &[PROTOCOL_VERSION as u8];
 // maximum tolerated delay between messages packets.
 const MAX_TOLERATED_DELAY_MS: u64 = 5000;
 
+// maximum size of messages waiting to be sent to a single peer: two
+// packets' worth.
+const MAX_OUTBOUND_SIZE: usize = 16 * 1024 * 1024;
+
 /// Number of packets. A bunch are reserved.
 pub const PACKET_COUNT: u8 = 128;
 
@@ -73,6 +79,11 @@ pub trait MessageHandler: Send + Sync {
 	/// If there is a significant overhead in this thread, then an attacker
 	/// can determine which kinds of messages we are listening for.
 	fn handle_messages(&self, message: &[Message]);
+
+	/// Note that a message was refused by the pool, and why.
+	///
+	/// Called for every refusal, so this should be cheap.
+	fn note_rejected(&self, _id: &H256, _reason: &AcceptError) {}
 }
 
 // errors in importing a whisper message.
@@ -117,17 +128,47 @@ impl fmt::Display for Error {
 	}
 }
 
-// sorts by work proved, descending.
+/// Reasons an envelope may be refused by the message pool.
+#[derive(Debug)]
+pub enum AcceptError {
+	/// The envelope is malformed or its timing is invalid.
+	Invalid(MessageError),
+	/// The message is already in the pool.
+	Known,
+	/// The message is larger than the whole pool.
+	TooLarge,
+	/// Not enough work proved to enter the pool.
+	InsufficientWork,
+}
+
+impl From<MessageError> for AcceptError {
+	fn from(err: MessageError) -> Self {
+		AcceptError::Invalid(err)
+	}
+}
+
+impl fmt::Display for AcceptError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			AcceptError::Invalid(ref err) => write!(f, "Invalid message: {}", err),
+			AcceptError::Known => write!(f, "Message already known."),
+			AcceptError::TooLarge => write!(f, "Message larger than the pool."),
+			AcceptError::InsufficientWork => write!(f, "Not enough work proved to enter the pool."),
+		}
+	}
+}
+
+// sorts by priority as of the pool's last ranking, descending.
 #[derive(PartialEq, Eq)]
 struct SortedEntry {
 	slab_id: usize,
-	work_proved: OrderedFloat<f64>,
+	priority: OrderedFloat<f64>,
 	expiry: SystemTime,
 }
 
 impl Ord for SortedEntry {
 	fn cmp(&self, other: &SortedEntry) -> Ordering {
-		self.work_proved.cmp(&other.work_proved)
+		self.priority.cmp(&other.priority).reverse()
 	}
 }
 
@@ -137,15 +178,46 @@ impl PartialOrd for SortedEntry {
 	}
 }
 
-// stores messages by two metrics: expiry and PoW rating
+/// Proof-of-work required of messages entering the pool.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowTarget {
+	/// A fixed minimum.
+	Fixed(f64),
+	/// Rises from `floor` when the pool is empty to `ceiling` when it is
+	/// full, recomputed at each rally.
+	Adaptive {
+		/// Minimum required of an empty pool.
+		floor: f64,
+		/// Minimum required of a full pool.
+		ceiling: f64,
+	},
+}
+
+impl PowTarget {
+	// minimum work required of a pool filled to the given fraction.
+	fn at_fill(&self, fill: f64) -> f64 {
+		match *self {
+			PowTarget::Fixed(pow) => pow,
+			PowTarget::Adaptive { floor, ceiling } =>
+				floor + (ceiling - floor) * fill.max(0f64).min(1f64),
+		}
+	}
+}
+
+// stores messages by two metrics: expiry and priority
 // when full, will accept messages above the minimum stored.
+//
+// priorities decay at different rates, so entries are ranked as of a single
+// point in time, refreshed on each prune rather than on every insert.
 struct Messages {
 	slab: ::slab::Slab<Message>,
 	sorted: Vec<SortedEntry>,
+	ranked_at: SystemTime,
 	known: HashSet<H256>,
 	removed_hashes: Vec<H256>,
 	cumulative_size: usize,
 	ideal_size: usize,
+	min_pow: f64,
 }
 
 impl Messages {
@@ -153,10 +225,12 @@ impl Messages {
 		Messages {
 			slab: ::slab::Slab::with_capacity(0),
 			sorted: Vec::new(),
+			ranked_at: UNIX_EPOCH,
 			known: HashSet::new(),
 			removed_hashes: Vec::new(),
 			cumulative_size: 0,
 			ideal_size: ideal_size,
+			min_pow: 0f64,
 		}
 	}
 
@@ -167,55 +241,83 @@ impl Messages {
 		self.known.reserve(additional);
 	}
 
-	// whether a message is not known and within the bounds of PoW.
-	fn may_accept(&self, message: &Message) -> bool {
-		!self.known.contains(message.hash()) && {
-			self.sorted.last().map_or(true, |entry| {
-				let work_proved = OrderedFloat(message.work_proved());
-				OrderedFloat(self.slab[entry.slab_id].work_proved()) < work_proved
-			})
+	// check whether a message is not known, has valid topics and, if we're
+	// full, has a higher priority than the least worthy message stored.
+	fn check_accept(&self, message: &Message) -> Result<(), AcceptError> {
+		if self.known.contains(message.hash()) { return Err(AcceptError::Known) }
+		if message.encoded_size() > self.ideal_size { return Err(AcceptError::TooLarge) }
+		message.check_topics()?;
+		if message.work_proved() < self.min_pow { return Err(AcceptError::InsufficientWork) }
+		if !self.is_full() { return Ok(()) }
+
+		let beats_minimum = self.sorted.last().map_or(true, |entry| {
+			entry.priority < OrderedFloat(message.priority(self.ranked_at))
+		});
+
+		if beats_minimum { Ok(()) } else { Err(AcceptError::InsufficientWork) }
+	}
+
+	// recompute priorities as of the given time and restore sorted order.
+	fn rerank(&mut self, now: SystemTime) {
+		{
+			let slab = &self.slab;
+			for entry in self.sorted.iter_mut() {
+				entry.priority = OrderedFloat(slab[entry.slab_id].priority(now));
+			}
 		}
+
+		self.sorted.sort();
+		self.ranked_at = now;
 	}
 
 	// insert a message into the store. for best performance,
 	// call `reserve` before inserting a bunch.
 	//
-	fn insert(&mut self, message: Message) -> bool {
+	// when full, lower priority messages are evicted to make space.
+	fn insert(&mut self, message: Message, now: SystemTime) -> bool {
 		if !self.known.insert(message.hash().clone()) { return false }
 
-		let work_proved = OrderedFloat(message.work_proved());
+		// nothing to compare against, so rank from now on.
+		if self.sorted.is_empty() { self.ranked_at = now }
+
+		let priority = OrderedFloat(message.priority(self.ranked_at));
 
-		// pop off entries by low PoW until we have enough space for the higher
-		// PoW message being inserted.
+		// pop off entries by low priority until we have enough space for the
+		// higher priority message being inserted.
 		let size_upon_insertion = self.cumulative_size + message.encoded_size();
 		if size_upon_insertion >= self.ideal_size {
 			let diff = size_upon_insertion - self.ideal_size;
+
 			let mut found_diff = 0;
+			let mut to_evict = Vec::new();
 			for entry in self.sorted.iter().rev() {
 				if found_diff >= diff { break }
 
-				// if we encounter a message with at least the PoW we're looking
-				// at, don't push that message out.
-				if entry.work_proved >= work_proved { return false }
+				// if we encounter a message with at least the priority we're
+				// looking at, don't push that message out.
+				if entry.priority >= priority { break }
+
 				found_diff += self.slab[entry.slab_id].encoded_size();
+				to_evict.push(entry.slab_id);
 			}
 
-			// message larger than ideal size.
-			if found_diff < diff { return false }
-
-			while found_diff > 0 {
-				let entry = self.sorted.pop()
-					.expect("found_diff built by traversing entries; therefore that many entries exist; qed");
-
-				let message = self.slab.remove(entry.slab_id)
-					.expect("sorted entry slab IDs always filled; qed");
+			// not enough lower priority messages, or message larger than ideal size.
+			if found_diff < diff {
+				self.known.remove(message.hash());
+				return false
+			}
 
-				found_diff -= message.encoded_size();
+			for slab_id in to_evict {
+				let message = self.slab.remove(slab_id)
+					.expect("evicted IDs taken from sorted entries; sorted entry slab IDs always filled; qed");
 
 				self.cumulative_size -= message.encoded_size();
 				self.known.remove(message.hash());
 				self.removed_hashes.push(message.hash().clone());
 			}
+
+			let slab = &self.slab;
+			self.sorted.retain(|entry| slab.contains(entry.slab_id));
 		}
 
 		let expiry = message.expiry();
@@ -227,7 +329,7 @@ impl Messages {
 
 		let sorted_entry = SortedEntry {
 			slab_id: id,
-			work_proved: work_proved,
+			priority: priority,
 			expiry: expiry,
 		};
 
@@ -238,9 +340,11 @@ impl Messages {
 		true
 	}
 
-	// prune expired messages, and then prune low proof-of-work messages
+	// prune expired messages, and then prune low priority messages
 	// until below ideal size.
 	fn prune(&mut self, now: SystemTime) -> Vec<H256> {
+		self.rerank(now);
+
 		{
 			let slab = &mut self.slab;
 			let known = &mut self.known;
@@ -254,14 +358,14 @@ impl Messages {
 				.map(|x| (true, x));
 
 			// second pass, we look at entries which aren't expired but in order
-			// by PoW
-			let low_proof = self.sorted.iter().rev()
+			// by priority
+			let low_priority = self.sorted.iter().rev()
 				.filter(|entry| entry.expiry > now)
 				.map(|x| (false, x));
 
-			for (is_expired, entry) in all_expired.chain(low_proof) {
+			for (is_expired, entry) in all_expired.chain(low_priority) {
 				// break once we've removed all expired entries
-				// or have taken enough low-work entries.
+				// or have taken enough low-priority entries.
 				if !is_expired && *cumulative_size <= *ideal_size {
 					break
 				}
@@ -291,12 +395,28 @@ impl Messages {
 		self.cumulative_size >= self.ideal_size
 	}
 
+	// fraction of the ideal size in use.
+	fn fill(&self) -> f64 {
+		match self.ideal_size {
+			0 => 1f64,
+			ideal => self.cumulative_size as f64 / ideal as f64,
+		}
+	}
+
 	fn status(&self) -> PoolStatus {
+		let least_stored = if self.is_full() {
+			// a fresh message's priority is its work proved.
+			self.sorted.last().map(|entry| entry.priority.0)
+		} else {
+			None
+		};
+
+		let min_pow = if self.min_pow > 0f64 { Some(self.min_pow) } else { None };
+
 		PoolStatus {
-			required_pow: if self.is_full() {
-				self.sorted.last().map(|entry| entry.work_proved.0)
-			} else {
-				None
+			required_pow: match (least_stored, min_pow) {
+				(Some(a), Some(b)) => Some(a.max(b)),
+				(a, b) => a.or(b),
 			},
 			message_count: self.sorted.len(),
 			cumulative_size: self.cumulative_size,
@@ -310,19 +430,67 @@ enum State {
 	Confirmed,
 }
 
+// messages waiting to be sent to a peer, bounded by encoded size. once full,
+// the oldest are dropped to make room, so a slow peer can't hold on to
+// unbounded memory.
+struct OutboundQueue {
+	messages: VecDeque<Message>,
+	size: usize,
+	capacity: usize,
+}
+
+impl OutboundQueue {
+	fn new(capacity: usize) -> Self {
+		OutboundQueue {
+			messages: VecDeque::new(),
+			size: 0,
+			capacity: capacity,
+		}
+	}
+
+	// queue a message, returning the hashes of the oldest messages which had
+	// to be dropped to fit it.
+	fn push(&mut self, message: Message) -> Vec<H256> {
+		let mut dropped = Vec::new();
+		while self.size + message.encoded_size() > self.capacity {
+			match self.pop_front() {
+				Some(old) => dropped.push(old.hash().clone()),
+				None => break,
+			}
+		}
+
+		self.size += message.encoded_size();
+		self.messages.push_back(message);
+		dropped
+	}
+
+	fn pop_front(&mut self) -> Option<Message> {
+		let message = self.messages.pop_front();
+		if let Some(ref message) = message {
+			self.size -= message.encoded_size();
+		}
+
+		message
+	}
+}
+
 #[allow(dead_code)] // for node key. this will be useful for topic routing.
 struct Peer {
 	node_key: NodeId,
 	state: State,
 	known_messages: HashSet<H256>,
 	topic_filter: Option<H512>,
+	advertised_filter: Option<H512>, // our topic filter, as last sent to the peer.
+	advertised_pow: f64, // our PoW requirement, as last sent to the peer.
 	pow_requirement: f64,
 	is_parity: bool,
+	outbound: OutboundQueue,
 	_protocol_version: usize,
 }
 
 impl Peer {
-	// note that a message has been evicted from the queue.
+	// note that messages have been evicted from the pool or dropped from
+	// the outbound queue, so they may be sent again.
 	fn note_evicted(&mut self, messages: &[H256]) {
 		for message_hash in messages {
 			self.known_messages.remove(message_hash);
@@ -341,10 +509,10 @@ impl Peer {
 			.map_or(true, |filter| &(filter & message.bloom()) == message.bloom())
 	}
 
-	// note a message as known. returns true if it was already
-	// known, false otherwise.
-	fn note_known(&mut self, message: &Message) -> bool {
-		self.known_messages.insert(message.hash().clone())
+	// note a message as known by its hash. returns false if it was already
+	// known, true otherwise.
+	fn note_known(&mut self, hash: &H256) -> bool {
+		self.known_messages.insert(hash.clone())
 	}
 
 	fn set_topic_filter(&mut self, topic: H512) {
@@ -413,51 +581,249 @@ impl<'a> Context for NetworkContext<'a> {
 	}
 }
 
+// decode a big-endian encoded PoW value.
+fn decode_pow(rlp: UntrustedRlp) -> Result<f64, Error> {
+	use byteorder::{ByteOrder, BigEndian};
+
+	let bytes: Vec<u8> = rlp.as_val()?;
+	if bytes.len() != ::std::mem::size_of::<f64>() {
+		return Err(Error::InvalidPowReq);
+	}
+
+	// as of byteorder 1.1.0, this is always defined.
+	Ok(BigEndian::read_f64(&bytes[..]))
+}
+
+// encode a PoW requirement as big-endian bytes.
+fn encode_pow(pow: f64) -> [u8; 8] {
+	use byteorder::{ByteOrder, BigEndian};
+
+	let mut bytes = [0u8; 8];
+	BigEndian::write_f64(&mut bytes, pow);
+	bytes
+}
+
+/// What a node does with messages it accepts from the network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhisperRole {
+	/// Pool and relay messages, and hand them to the message handler.
+	Endpoint,
+	/// Pool and relay messages only. The message handler is never invoked,
+	/// so no filter matching or decryption happens.
+	Relay,
+}
+
+// decode an RLP-encoded envelope and insert it into the pool.
+fn accept_envelope(messages: &mut Messages, envelope: UntrustedRlp, now: SystemTime)
+	-> Result<Message, AcceptError>
+{
+	let message = Message::decode(envelope, now)?;
+
+	messages.check_accept(&message)?;
+
+	if messages.insert(message.clone(), now) {
+		Ok(message)
+	} else {
+		Err(AcceptError::InsufficientWork)
+	}
+}
+
 /// The whisper network protocol handler.
 pub struct Network<T> {
 	messages: Arc<RwLock<Messages>>,
 	handler: T,
 	peers: RwLock<HashMap<PeerId, Mutex<Peer>>>,
 	node_key: RwLock<NodeId>,
+	topic_filter: RwLock<Option<H512>>,
+	clock: Box<Clock>,
+	role: WhisperRole,
+	pow_target: PowTarget,
+	outbound_dropped: AtomicUsize,
 }
 
 // public API.
 impl<T> Network<T> {
 	/// Create a new network handler.
 	pub fn new(messages_size_bytes: usize, handler: T) -> Self {
+		Network::with_clock(messages_size_bytes, handler, Box::new(SystemClock))
+	}
+
+	/// Create a new network handler which takes the current time from the
+	/// given clock.
+	pub fn with_clock(messages_size_bytes: usize, handler: T, clock: Box<Clock>) -> Self {
 		Network {
 			messages: Arc::new(RwLock::new(Messages::new(messages_size_bytes))),
 			handler: handler,
 			peers: RwLock::new(HashMap::new()),
 			node_key: RwLock::new(Default::default()),
+			topic_filter: RwLock::new(None),
+			clock: clock,
+			role: WhisperRole::Endpoint,
+			pow_target: PowTarget::Fixed(0f64),
+			outbound_dropped: AtomicUsize::new(0),
 		}
 	}
 
+	/// Set the proof-of-work required of messages from peers. Defaults to
+	/// `PowTarget::Fixed(0.0)`.
+	pub fn with_pow_target(mut self, target: PowTarget) -> Self {
+		{
+			let mut messages = self.messages.write();
+			messages.min_pow = target.at_fill(messages.fill());
+		}
+
+		self.pow_target = target;
+		self
+	}
+
+	/// Proof-of-work currently required of messages from peers.
+	pub fn effective_pow(&self) -> f64 {
+		self.messages.read().min_pow
+	}
+
+	/// Set the role of this node. Defaults to `WhisperRole::Endpoint`.
+	pub fn with_role(mut self, role: WhisperRole) -> Self {
+		self.role = role;
+		self
+	}
+
+	/// Set the topic bloom advertised to peers. New peers receive it upon
+	/// connection and connected peers at the next rally.
+	/// `None` asks peers for all messages. Nothing is advertised until
+	/// this is first called, so advertising is left to the caller to opt into.
+	pub fn set_topic_filter(&self, filter: Option<H512>) {
+		*self.topic_filter.write() = filter;
+	}
+
+	/// Build the status packet: our protocol version, the PoW required to
+	/// enter our message pool, and our topic filter if we have one.
+	pub fn status_packet(&self) -> Vec<u8> {
+		let pow_bytes = encode_pow(self.required_pow());
+		let topic_filter = self.topic_filter.read();
+
+		let mut stream = RlpStream::new_list(if topic_filter.is_some() { 3 } else { 2 });
+		stream.append(&PROTOCOL_VERSION).append(&&pow_bytes[..]);
+
+		if let Some(ref filter) = *topic_filter {
+			stream.append(filter);
+		}
+
+		stream.out()
+	}
+
+	// the PoW required to enter our message pool, as told to peers.
+	fn required_pow(&self) -> f64 {
+		self.messages.read().status().required_pow.unwrap_or(0f64)
+	}
+
 	/// Post a message to the whisper network to be relayed.
-	pub fn post_message<C: Context>(&self, message: Message, context: &C) -> bool
+	///
+	/// Returns the envelope ID if the message was accepted into the pool.
+	pub fn post_message<C: Context>(&self, message: Message, context: &C) -> Option<H256>
+		where T: MessageHandler
+	{
+		let id = message.hash().clone();
+		let ok = self.messages.write().insert(message, self.clock.now());
+		if ok {
+			self.rally(context);
+			Some(id)
+		} else {
+			None
+		}
+	}
+
+	/// Validate and pool a batch of envelopes, such as those carried by a
+	/// single messages packet.
+	///
+	/// Each envelope is checked on its own, so one bad envelope doesn't
+	/// prevent the rest from being accepted. Returns an outcome per envelope,
+	/// in order. Accepted messages are relayed at the next rally.
+	pub fn accept_batch(&self, envelopes: Vec<Envelope>) -> Vec<Result<(), AcceptError>>
 		where T: MessageHandler
 	{
-		let ok = self.messages.write().insert(message);
-		if ok { self.rally(context) }
-		ok
+		let encoded: Vec<_> = envelopes.iter().map(::rlp::encode).collect();
+		self.accept_encoded(encoded.iter().map(|envelope| UntrustedRlp::new(envelope)))
 	}
 
 	/// Get number of messages and amount of memory used by them.
 	pub fn pool_status(&self) -> PoolStatus {
 		self.messages.read().status()
 	}
+
+	/// Number of messages dropped from peers' send queues because those
+	/// peers were too slow to receive them, since the handler was created.
+	pub fn dropped_outbound(&self) -> usize {
+		self.outbound_dropped.load(AtomicOrdering::Relaxed)
+	}
 }
 
 impl<T: MessageHandler> Network<T> {
+	// log the pool's decision on a message, telling the handler about
+	// refusals. per-message logs stay at trace and debug level.
+	fn note_decision(&self, id: &H256, result: &Result<(), AcceptError>) {
+		match *result {
+			Ok(()) => trace!(target: "whisper", "Accepted message {}", id),
+			Err(ref reason) => {
+				match *reason {
+					AcceptError::Known => trace!(target: "whisper", "Ignoring known message {}", id),
+					_ => debug!(target: "whisper", "Rejected message {}: {}", id, reason),
+				}
+
+				self.handler.note_rejected(id, reason);
+			}
+		}
+	}
+
+	// decode and pool RLP-encoded envelopes, reporting each outcome, and
+	// handle the accepted ones locally.
+	fn accept_encoded<'a, I>(&self, envelopes: I) -> Vec<Result<(), AcceptError>>
+		where I: IntoIterator<Item=UntrustedRlp<'a>>, I::IntoIter: ExactSizeIterator
+	{
+		let envelopes = envelopes.into_iter();
+		let now = self.clock.now();
+		let mut messages = self.messages.write();
+		let mut accepted = Vec::new();
+
+		messages.reserve(envelopes.len());
+		let results = envelopes.map(|envelope| {
+			let id = envelope_id(envelope.as_raw());
+			let result = accept_envelope(&mut messages, envelope, now)
+				.map(|message| accepted.push(message));
+
+			self.note_decision(&id, &result);
+			result
+		}).collect();
+
+		self.handle_locally(&accepted);
+		results
+	}
+
+	// pass accepted messages to the handler, unless only relaying.
+	fn handle_locally(&self, messages: &[Message]) {
+		if self.role == WhisperRole::Endpoint {
+			self.handler.handle_messages(messages);
+		}
+	}
+
 	fn rally<C: Context>(&self, io: &C) {
 		// cannot be greater than 16MB (protocol limitation)
 		const MAX_MESSAGES_PACKET_SIZE: usize = 8 * 1024 * 1024;
 
-		// prune messages.
-		let now = SystemTime::now();
-		let pruned_hashes = self.messages.write().prune(now);
+		// prune messages and adapt the work target to what's left.
+		let now = self.clock.now();
+		let pruned_hashes = {
+			let mut messages = self.messages.write();
+			let pruned_hashes = messages.prune(now);
 
+			let fill = messages.fill();
+			messages.min_pow = self.pow_target.at_fill(fill);
+
+			pruned_hashes
+		};
+
+		let topic_filter = *self.topic_filter.read();
 		let messages = self.messages.read();
+		let required_pow = messages.status().required_pow.unwrap_or(0f64);
 		let peers = self.peers.read();
 
 		// send each peer a packet with new messages it may find relevant.
@@ -481,19 +847,56 @@ impl<T: MessageHandler> Network<T> {
 				State::Confirmed => {}
 			}
 
-			// construct packet, skipping messages the peer won't accept.
-			let mut stream = RlpStream::new();
-			stream.begin_unbounded_list();
+			// tell the peer about changes to our topic filter. a full bloom
+			// asks for everything.
+			if peer_data.advertised_filter != topic_filter {
+				let bloom = topic_filter.unwrap_or(H512([0xff; 64]));
+				io.send(*peer_id, packet::TOPIC_FILTER, ::rlp::encode(&bloom).to_vec());
+				peer_data.advertised_filter = topic_filter;
+			}
+
+			// likewise for changes to the work needed to enter our pool.
+			if peer_data.advertised_pow != required_pow {
+				let pow_bytes = encode_pow(required_pow);
+				io.send(*peer_id, packet::POW_REQUIREMENT, ::rlp::encode(&&pow_bytes[..]).to_vec());
+				peer_data.advertised_pow = required_pow;
+			}
 
+			// queue messages the peer will accept. any which don't fit in
+			// one queue's worth are left for the next rally.
+			let mut queued_size = 0;
 			for message in messages.iter() {
 				if !peer_data.will_accept(message) { continue }
 
-				if stream.estimate_size(message.encoded_size()) > MAX_MESSAGES_PACKET_SIZE {
+				let size = message.encoded_size();
+				if queued_size + size > peer_data.outbound.capacity { continue }
+
+				peer_data.note_known(message.hash());
+				let dropped = peer_data.outbound.push(message.clone());
+				queued_size += size;
+
+				if !dropped.is_empty() {
+					self.outbound_dropped.fetch_add(dropped.len(), AtomicOrdering::Relaxed);
+					peer_data.note_evicted(&dropped);
+				}
+			}
+
+			// construct packet from the front of the queue, skipping
+			// messages which expired while waiting.
+			let mut stream = RlpStream::new();
+			stream.begin_unbounded_list();
+
+			while let Some(size) = peer_data.outbound.messages.front().map(|m| m.encoded_size()) {
+				if stream.estimate_size(size) > MAX_MESSAGES_PACKET_SIZE {
 					break;
 				}
 
-				peer_data.note_known(message);
-				stream.append(message.envelope());
+				let message = peer_data.outbound.pop_front()
+					.expect("front of queue checked above; qed");
+
+				if message.expiry() > now {
+					stream.append(message.envelope());
+				}
 			}
 
 			stream.complete_unbounded_list();
@@ -503,14 +906,48 @@ impl<T: MessageHandler> Network<T> {
 	}
 
 	// handle status packet from peer.
-	fn on_status(&self, peer: &PeerId, _status: UntrustedRlp)
+	//
+	// the status may be an empty list, or carry the protocol version followed
+	// by the peer's optional PoW requirement and topic filter.
+	fn on_status(&self, peer: &PeerId, status: UntrustedRlp)
 		-> Result<(), Error>
 	{
+		let peer_id = *peer;
 		let peers = self.peers.read();
 
 		match peers.get(peer) {
 			Some(peer) => {
-				peer.lock().state = State::Confirmed;
+				let item_count = status.item_count()?;
+
+				// the PoW requirement and topic filter are optional, so
+				// malformed ones are ignored rather than failing the handshake.
+				let pow_requirement = match item_count {
+					0 | 1 => None,
+					_ => match status.at(1).map_err(Into::into).and_then(decode_pow) {
+						Ok(req) if req == 0f64 || req.is_normal() => Some(req),
+						Ok(_) | Err(_) => {
+							debug!(target: "whisper", "Ignoring invalid PoW requirement in status of peer {}", peer_id);
+							None
+						}
+					},
+				};
+
+				let topic_filter = match item_count {
+					0 | 1 | 2 => None,
+					_ => match status.val_at(2) {
+						Ok(filter) => Some(filter),
+						Err(err) => {
+							debug!(target: "whisper", "Ignoring invalid topic filter in status of peer {}: {}", peer_id, err);
+							None
+						}
+					},
+				};
+
+				let mut peer = peer.lock();
+				if let Some(req) = pow_requirement { peer.set_pow_requirement(req) }
+				if let Some(filter) = topic_filter { peer.set_topic_filter(filter) }
+
+				peer.state = State::Confirmed;
 				Ok(())
 			}
 			None => {
@@ -523,7 +960,7 @@ impl<T: MessageHandler> Network<T> {
 	fn on_messages(&self, peer: &PeerId, message_packet: UntrustedRlp)
 		-> Result<(), Error>
 	{
-		let mut messages_vec = {
+		let envelopes: Vec<_> = {
 			let peers = self.peers.read();
 			let peer = match peers.get(peer) {
 				Some(peer) => peer,
@@ -539,28 +976,14 @@ impl<T: MessageHandler> Network<T> {
 				return Err(Error::UnexpectedMessage);
 			}
 
-			let now = SystemTime::now();
-			let mut messages_vec = message_packet.iter().map(|rlp| Message::decode(rlp, now))
-				.collect::<Result<Vec<_>, _>>()?;
-
-			if messages_vec.is_empty() { return Ok(()) }
-
 			// disallow duplicates in packet.
-			messages_vec.retain(|message| peer.note_known(&message));
-			messages_vec
+			message_packet.iter()
+				.filter(|envelope| peer.note_known(&envelope_id(envelope.as_raw())))
+				.collect()
 		};
 
-		// import for relaying.
-		let mut messages = self.messages.write();
-
-		messages_vec.retain(|message| messages.may_accept(&message));
-		messages.reserve(messages_vec.len());
-
-		self.handler.handle_messages(&messages_vec);
-
-		for message in messages_vec {
-			messages.insert(message);
-		}
+		// import for relaying. invalid envelopes are rejected individually.
+		self.accept_encoded(envelopes);
 
 		Ok(())
 	}
@@ -568,8 +991,6 @@ impl<T: MessageHandler> Network<T> {
 	fn on_pow_requirement(&self, peer: &PeerId, requirement: UntrustedRlp)
 		-> Result<(), Error>
 	{
-		use byteorder::{ByteOrder, BigEndian};
-
 		let peers = self.peers.read();
 		match peers.get(peer) {
 			Some(peer) => {
@@ -578,15 +999,10 @@ impl<T: MessageHandler> Network<T> {
 				if let State::Unconfirmed(_) = peer.state {
 					return Err(Error::UnexpectedMessage);
 				}
-				let bytes: Vec<u8> = requirement.as_val()?;
-				if bytes.len() != ::std::mem::size_of::<f64>() {
-					return Err(Error::InvalidPowReq);
-				}
-
-				// as of byteorder 1.1.0, this is always defined.
-				let req = BigEndian::read_f64(&bytes[..]);
+				let req = decode_pow(requirement)?;
 
-				if !req.is_normal() {
+				// zero lifts a previous requirement.
+				if req != 0f64 && !req.is_normal() {
 					return Err(Error::InvalidPowReq);
 				}
 
@@ -644,17 +1060,25 @@ impl<T: MessageHandler> Network<T> {
 			}
 		};
 
+		// recorded before building the status, so a change in between is
+		// sent again at the next rally.
+		let advertised_filter = *self.topic_filter.read();
+		let advertised_pow = self.required_pow();
+
 		self.peers.write().insert(*peer, Mutex::new(Peer {
 			node_key: node_key,
-			state: State::Unconfirmed(SystemTime::now()),
+			state: State::Unconfirmed(self.clock.now()),
 			known_messages: HashSet::new(),
 			topic_filter: None,
+			advertised_filter: advertised_filter,
+			advertised_pow: advertised_pow,
 			pow_requirement: 0f64,
 			is_parity: io.protocol_version(PARITY_PROTOCOL_ID, *peer).is_some(),
+			outbound: OutboundQueue::new(MAX_OUTBOUND_SIZE),
 			_protocol_version: version,
 		}));
 
-		io.send(*peer, packet::STATUS, ::rlp::EMPTY_LIST_RLP.to_vec());
+		io.send(*peer, packet::STATUS, self.status_packet());
 	}
 
 	fn on_disconnect(&self, peer: &PeerId) {
@@ -721,3 +1145,581 @@ impl ::network::NetworkProtocolHandler for ParityExtensions {
 
 	fn timeout(&self, _io: &NetworkContext, _timer: TimerToken) { }
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use message::Topic;
+	use smallvec::SmallVec;
+
+	struct NullHandler;
+
+	impl MessageHandler for NullHandler {
+		fn handle_messages(&self, _messages: &[Message]) {}
+	}
+
+	#[derive(Default)]
+	struct RecordingHandler(Mutex<Vec<(H256, String)>>);
+
+	impl MessageHandler for Arc<RecordingHandler> {
+		fn handle_messages(&self, _messages: &[Message]) {}
+
+		fn note_rejected(&self, id: &H256, reason: &AcceptError) {
+			self.0.lock().push((id.clone(), format!("{:?}", reason)));
+		}
+	}
+
+	struct NullContext;
+
+	impl Context for NullContext {
+		fn disconnect_peer(&self, _: PeerId) {}
+		fn disable_peer(&self, _: PeerId) {}
+		fn node_key(&self, _: PeerId) -> Option<NodeId> { None }
+		fn protocol_version(&self, _: ProtocolId, _: PeerId) -> Option<u8> { None }
+		fn send(&self, _: PeerId, _: u8, _: Vec<u8>) {}
+	}
+
+	#[derive(Default)]
+	struct RecordingContext(Mutex<Vec<(PeerId, u8, Vec<u8>)>>);
+
+	impl Context for RecordingContext {
+		fn disconnect_peer(&self, _: PeerId) {}
+		fn disable_peer(&self, _: PeerId) {}
+		fn node_key(&self, _: PeerId) -> Option<NodeId> { None }
+		fn protocol_version(&self, _: ProtocolId, _: PeerId) -> Option<u8> { None }
+		fn send(&self, peer: PeerId, packet_id: u8, data: Vec<u8>) {
+			self.0.lock().push((peer, packet_id, data));
+		}
+	}
+
+	fn unconfirmed_peer() -> Peer {
+		Peer {
+			node_key: Default::default(),
+			state: State::Unconfirmed(SystemTime::now()),
+			known_messages: HashSet::new(),
+			topic_filter: None,
+			advertised_filter: None,
+			advertised_pow: 0f64,
+			pow_requirement: 0f64,
+			is_parity: false,
+			outbound: OutboundQueue::new(MAX_OUTBOUND_SIZE),
+			_protocol_version: PROTOCOL_VERSION,
+		}
+	}
+
+	fn network_with_peer(peer_id: PeerId) -> Network<NullHandler> {
+		let network = Network::new(1024, NullHandler);
+		network.peers.write().insert(peer_id, Mutex::new(unconfirmed_peer()));
+		network
+	}
+
+	// add a peer which has sent us an empty status.
+	fn add_confirmed_peer<T: MessageHandler>(network: &Network<T>, peer_id: PeerId) {
+		network.peers.write().insert(peer_id, Mutex::new(unconfirmed_peer()));
+		network.on_status(&peer_id, UntrustedRlp::new(&::rlp::EMPTY_LIST_RLP)).unwrap();
+	}
+
+	fn now_secs() -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+	}
+
+	// an envelope with a 100 second TTL. nonces below 128 encode to a single
+	// byte, keeping the encoded sizes equal.
+	fn envelope(topic: Topic, nonce: u64, expiry: u64) -> Envelope {
+		Envelope {
+			expiry: expiry,
+			ttl: 100,
+			data: vec![9; 64],
+			topics: SmallVec::from_slice(&[topic]),
+			nonce: nonce,
+		}
+	}
+
+	fn work(envelope: &Envelope) -> f64 {
+		let encoded = ::rlp::encode(envelope);
+		Message::decode(UntrustedRlp::new(&*encoded), SystemTime::now()).unwrap().work_proved()
+	}
+
+	// envelopes with single-byte nonces, in ascending order of work proved.
+	fn by_work(expiry: u64) -> Vec<Envelope> {
+		let mut envelopes: Vec<_> = (0..128)
+			.map(|nonce| envelope(Topic([1, 2, 3, 4]), nonce, expiry))
+			.collect();
+
+		envelopes.sort_by(|a, b| work(a).partial_cmp(&work(b)).unwrap());
+		envelopes
+	}
+
+	fn weakest_and_strongest(expiry: u64) -> (Envelope, Envelope) {
+		let mut envelopes = by_work(expiry);
+		let strongest = envelopes.pop().unwrap();
+
+		(envelopes.swap_remove(0), strongest)
+	}
+
+	#[test]
+	fn status_stores_advertised_values() {
+		use byteorder::{ByteOrder, BigEndian};
+
+		let bloom = Topic([1, 2, 3, 4]).bloom();
+		let mut pow_bytes = [0u8; 8];
+		BigEndian::write_f64(&mut pow_bytes, 2.5);
+
+		let mut stream = RlpStream::new_list(3);
+		stream.append(&PROTOCOL_VERSION).append(&&pow_bytes[..]).append(&bloom);
+
+		let network = network_with_peer(1);
+		network.on_status(&1, UntrustedRlp::new(&stream.out())).unwrap();
+
+		let peers = network.peers.read();
+		let peer = peers[&1].lock();
+
+		assert!(peer.can_send_messages());
+		assert_eq!(peer.pow_requirement, 2.5);
+		assert_eq!(peer.topic_filter, Some(bloom));
+	}
+
+	#[test]
+	fn no_topic_filter_advertised_by_default() {
+		let local = Network::new(1024, NullHandler);
+		let status = local.status_packet();
+		assert_eq!(UntrustedRlp::new(&status).item_count().unwrap(), 2);
+
+		let remote = network_with_peer(1);
+		remote.on_status(&1, UntrustedRlp::new(&status)).unwrap();
+		assert_eq!(remote.peers.read()[&1].lock().topic_filter, None);
+	}
+
+	#[test]
+	fn status_packet_round_trip() {
+		let bloom = Topic([1, 2, 3, 4]).bloom();
+
+		let local = Network::new(1024, NullHandler);
+		local.set_topic_filter(Some(bloom));
+
+		let remote = network_with_peer(1);
+		remote.on_status(&1, UntrustedRlp::new(&local.status_packet())).unwrap();
+
+		let peers = remote.peers.read();
+		let peer = peers[&1].lock();
+
+		assert!(peer.can_send_messages());
+		assert_eq!(peer.pow_requirement, 0f64);
+		assert_eq!(peer.topic_filter, Some(bloom));
+	}
+
+	#[test]
+	fn status_ignores_malformed_optional_fields() {
+		let mut stream = RlpStream::new_list(3);
+		stream.append(&PROTOCOL_VERSION).append(&&[1u8, 2, 3][..]).append(&&[4u8, 5][..]);
+
+		let network = network_with_peer(1);
+		network.on_status(&1, UntrustedRlp::new(&stream.out())).unwrap();
+
+		let peers = network.peers.read();
+		let peer = peers[&1].lock();
+
+		assert!(peer.can_send_messages());
+		assert_eq!(peer.pow_requirement, 0f64);
+		assert_eq!(peer.topic_filter, None);
+	}
+
+	#[test]
+	fn topic_filter_changes_sent_at_rally() {
+		let bloom = Topic([1, 2, 3, 4]).bloom();
+
+		let network = Network::new(1024, NullHandler);
+		add_confirmed_peer(&network, 1);
+
+		let io = RecordingContext::default();
+		network.set_topic_filter(Some(bloom));
+		network.rally(&io);
+		network.rally(&io);
+
+		network.set_topic_filter(None);
+		network.rally(&io);
+
+		let filters: Vec<H512> = io.0.lock().iter()
+			.filter(|&&(_, packet_id, _)| packet_id == packet::TOPIC_FILTER)
+			.map(|&(_, _, ref data)| UntrustedRlp::new(data).as_val().unwrap())
+			.collect();
+
+		assert_eq!(filters, vec![bloom, H512([0xff; 64])]);
+	}
+
+	#[test]
+	fn outbound_queue_drops_oldest() {
+		use message::CreateParams;
+
+		let message = |payload| Message::create(CreateParams {
+			ttl: 100,
+			payload: payload,
+			topics: vec![Topic([1, 2, 3, 4])],
+			work: 0,
+		}).unwrap();
+
+		let messages: Vec<_> = (0..3).map(|i| message(vec![i])).collect();
+
+		let mut queue = OutboundQueue::new(messages[0].encoded_size() * 2);
+		assert!(queue.push(messages[0].clone()).is_empty());
+		assert!(queue.push(messages[1].clone()).is_empty());
+		assert_eq!(queue.push(messages[2].clone()), vec![messages[0].hash().clone()]);
+		assert_eq!(queue.size, messages[0].encoded_size() * 2);
+
+		let queued: Vec<_> = queue.messages.iter().map(|m| m.hash().clone()).collect();
+		assert_eq!(queued, vec![messages[1].hash().clone(), messages[2].hash().clone()]);
+	}
+
+	#[test]
+	fn dropped_outbound_survives_disconnect() {
+		use message::CreateParams;
+
+		let network = Network::new(1024, NullHandler);
+		add_confirmed_peer(&network, 1);
+
+		let envelopes = vec![envelope(Topic([1, 2, 3, 4]), 0, now_secs() + 100)];
+		assert!(network.accept_batch(envelopes).iter().all(|r| r.is_ok()));
+		let pooled_size = network.messages.read().iter().next().unwrap().encoded_size();
+
+		// a message left over from an earlier rally, which must make room.
+		let stale = Message::create(CreateParams {
+			ttl: 100,
+			payload: vec![1],
+			topics: vec![Topic([1, 2, 3, 4])],
+			work: 0,
+		}).unwrap();
+
+		{
+			let peers = network.peers.read();
+			let mut peer = peers[&1].lock();
+			peer.outbound = OutboundQueue::new(pooled_size);
+			peer.note_known(stale.hash());
+			peer.outbound.push(stale.clone());
+		}
+
+		network.rally(&NullContext);
+		assert_eq!(network.dropped_outbound(), 1);
+		assert!(!network.peers.read()[&1].lock().known_messages.contains(stale.hash()));
+
+		network.on_disconnect(&1);
+		assert_eq!(network.dropped_outbound(), 1);
+	}
+
+	#[test]
+	fn queues_only_what_fits() {
+		let expiry = now_secs() + 100;
+		let network = Network::new(1024, NullHandler);
+		add_confirmed_peer(&network, 1);
+
+		let envelopes = (0..2).map(|nonce| envelope(Topic([1, 2, 3, 4]), nonce, expiry)).collect();
+		assert!(network.accept_batch(envelopes).iter().all(|r| r.is_ok()));
+		let pooled_size = network.messages.read().iter().next().unwrap().encoded_size();
+		network.peers.read()[&1].lock().outbound = OutboundQueue::new(pooled_size);
+
+		// one message per rally, and neither lost.
+		let io = RecordingContext::default();
+		network.rally(&io);
+		network.rally(&io);
+
+		let relayed: Vec<Envelope> = io.0.lock().iter()
+			.filter(|&&(_, packet_id, _)| packet_id == packet::MESSAGES)
+			.flat_map(|&(_, _, ref data)| UntrustedRlp::new(data).as_list::<Envelope>().unwrap())
+			.collect();
+
+		assert_eq!(relayed.len(), 2);
+		assert_eq!(network.dropped_outbound(), 0);
+	}
+
+	#[test]
+	fn topic_filter_withholds_unmatched() {
+		let expiry = now_secs() + 100;
+		let wanted = envelope(Topic([1, 2, 3, 4]), 0, expiry);
+		let unwanted = envelope(Topic([5, 6, 7, 8]), 0, expiry);
+
+		let network = Network::new(1024, NullHandler);
+		add_confirmed_peer(&network, 1);
+		network.on_topic_filter(&1, UntrustedRlp::new(&::rlp::encode(&Topic([1, 2, 3, 4]).bloom()))).unwrap();
+
+		assert!(network.accept_batch(vec![wanted.clone(), unwanted]).iter().all(|r| r.is_ok()));
+
+		let io = RecordingContext::default();
+		network.rally(&io);
+
+		let sent = io.0.lock();
+		assert_eq!(sent.len(), 1);
+		assert_eq!((sent[0].0, sent[0].1), (1, packet::MESSAGES));
+
+		let relayed: Vec<Envelope> = UntrustedRlp::new(&sent[0].2).as_list().unwrap();
+		assert_eq!(relayed, vec![wanted]);
+	}
+
+	#[test]
+	fn pool_evicts_by_priority() {
+		let now = UNIX_EPOCH + Duration::from_secs(100_000);
+		let message = |expiry, nonce| {
+			let envelope = Envelope {
+				expiry: expiry,
+				ttl: 1000,
+				data: vec![9; 256],
+				topics: SmallVec::from_slice(&[Topic([1, 2, 3, 4])]),
+				nonce: nonce,
+			};
+
+			let encoded = ::rlp::encode(&envelope);
+			Message::decode(UntrustedRlp::new(&*encoded), now).unwrap()
+		};
+
+		let fresh = message(101_000, 0);
+		let nearly_expired = (1..).map(|nonce| message(100_001, nonce))
+			.find(|m| m.work_proved() > fresh.work_proved())
+			.unwrap();
+
+		// room for only one of the two.
+		let ideal_size = ::std::cmp::max(fresh.encoded_size(), nearly_expired.encoded_size()) + 1;
+		let mut pool = Messages::new(ideal_size);
+
+		let (fresh_hash, nearly_expired_hash) = (fresh.hash().clone(), nearly_expired.hash().clone());
+
+		assert!(pool.insert(nearly_expired.clone(), now));
+		assert!(pool.insert(fresh.clone(), now));
+
+		assert!(pool.known.contains(&fresh_hash));
+		assert!(!pool.known.contains(&nearly_expired_hash));
+		assert_eq!(pool.sorted.len(), 1);
+
+		// admission to a full pool follows the same ranking.
+		let mut pool = Messages::new(nearly_expired.encoded_size());
+		assert!(pool.insert(nearly_expired.clone(), now));
+		assert!(pool.is_full());
+		assert!(pool.check_accept(&fresh).is_ok());
+
+		let mut pool = Messages::new(fresh.encoded_size());
+		assert!(pool.insert(fresh, now));
+		assert!(pool.is_full());
+		match pool.check_accept(&nearly_expired) {
+			Err(AcceptError::InsufficientWork) => {},
+			ref other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn expires_with_injected_clock() {
+		use clock::MockClock;
+
+		let expiry = UNIX_EPOCH + Duration::from_secs(100_100);
+		let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(100_000)));
+		let network = Network::with_clock(1024 * 1024, NullHandler, Box::new(clock.clone()));
+
+		assert!(network.accept_batch(vec![envelope(Topic([1, 2, 3, 4]), 0, 100_100)])[0].is_ok());
+
+		clock.set(expiry - Duration::from_secs(1));
+		network.rally(&NullContext);
+		assert_eq!(network.pool_status().message_count, 1);
+
+		clock.set(expiry);
+		network.rally(&NullContext);
+		assert_eq!(network.pool_status().message_count, 0);
+	}
+
+	#[test]
+	fn relay_role_skips_handler() {
+		#[derive(Default)]
+		struct CountingHandler(AtomicUsize);
+
+		impl MessageHandler for Arc<CountingHandler> {
+			fn handle_messages(&self, messages: &[Message]) {
+				self.0.fetch_add(messages.len(), AtomicOrdering::SeqCst);
+			}
+		}
+
+		let envelope = envelope(Topic([1, 2, 3, 4]), 0, now_secs() + 100);
+
+		for &(role, handled) in &[(WhisperRole::Endpoint, 1), (WhisperRole::Relay, 0)] {
+			let handler = Arc::new(CountingHandler::default());
+			let network = Network::new(1024 * 1024, handler.clone()).with_role(role);
+
+			assert!(network.accept_batch(vec![envelope.clone()])[0].is_ok());
+			assert_eq!(network.pool_status().message_count, 1);
+			assert_eq!(handler.0.load(AtomicOrdering::SeqCst), handled);
+		}
+	}
+
+	#[test]
+	fn handler_told_of_rejections() {
+		let (weak, strong) = weakest_and_strongest(now_secs() + 100);
+
+		// fill the pool with a single message.
+		let handler = Arc::new(RecordingHandler::default());
+		let network = Network::new(::rlp::encode(&strong).len(), handler.clone());
+
+		assert!(network.accept_batch(vec![strong.clone()])[0].is_ok());
+		assert!(handler.0.lock().is_empty());
+
+		assert!(network.accept_batch(vec![weak.clone(), strong.clone()]).iter().all(|r| r.is_err()));
+		assert_eq!(*handler.0.lock(), vec![
+			(weak.id(), "InsufficientWork".to_owned()),
+			(strong.id(), "Known".to_owned()),
+		]);
+	}
+
+	#[test]
+	fn adaptive_pow_follows_pool_pressure() {
+		use clock::MockClock;
+
+		let now_secs = 100_000;
+		let envelope = |nonce| envelope(Topic([1, 2, 3, 4]), nonce, now_secs + 100);
+
+		let size = ::rlp::encode(&envelope(0)).len();
+		let clock = Arc::new(MockClock::new(UNIX_EPOCH + Duration::from_secs(now_secs)));
+		let target = PowTarget::Adaptive { floor: 0f64, ceiling: 1_000_000f64 };
+		let network = Network::with_clock(size * 4, NullHandler, Box::new(clock.clone()))
+			.with_pow_target(target);
+
+		assert_eq!(network.effective_pow(), 0f64);
+
+		let results = network.accept_batch((0..3).map(envelope).collect());
+		assert!(results.iter().all(|r| r.is_ok()));
+
+		network.rally(&NullContext);
+		let raised = network.effective_pow();
+		assert!(raised > 0f64);
+		assert_eq!(network.pool_status().required_pow, Some(raised));
+
+		// everything expires, the pool drains, and the target relaxes.
+		clock.advance(Duration::from_secs(100));
+		network.rally(&NullContext);
+		assert_eq!(network.pool_status().message_count, 0);
+		assert_eq!(network.effective_pow(), 0f64);
+	}
+
+	#[test]
+	fn pow_changes_sent_at_rally() {
+		let now_secs = now_secs();
+		let envelope = |nonce| envelope(Topic([1, 2, 3, 4]), nonce, now_secs + 100);
+
+		let size = ::rlp::encode(&envelope(0)).len();
+		let target = PowTarget::Adaptive { floor: 0f64, ceiling: 1_000_000f64 };
+		let network = Network::new(size * 4, NullHandler).with_pow_target(target);
+		add_confirmed_peer(&network, 1);
+
+		let io = RecordingContext::default();
+		network.rally(&io);
+
+		assert!(network.accept_batch((0..3).map(envelope).collect()).iter().all(|r| r.is_ok()));
+		network.rally(&io);
+		network.rally(&io);
+
+		let sent: Vec<f64> = io.0.lock().iter()
+			.filter(|&&(_, packet_id, _)| packet_id == packet::POW_REQUIREMENT)
+			.map(|&(_, _, ref data)| decode_pow(UntrustedRlp::new(data)).unwrap())
+			.collect();
+
+		assert_eq!(sent, vec![network.effective_pow()]);
+		assert!(sent[0] > 0f64);
+	}
+
+	#[test]
+	fn pow_requirement_may_drop_to_zero() {
+		let network = Network::new(1024, NullHandler);
+		add_confirmed_peer(&network, 1);
+
+		let requirement = |pow| ::rlp::encode(&&encode_pow(pow)[..]).to_vec();
+		network.on_pow_requirement(&1, UntrustedRlp::new(&requirement(2.5))).unwrap();
+		assert_eq!(network.peers.read()[&1].lock().pow_requirement, 2.5);
+
+		network.on_pow_requirement(&1, UntrustedRlp::new(&requirement(0f64))).unwrap();
+		assert_eq!(network.peers.read()[&1].lock().pow_requirement, 0f64);
+	}
+
+	#[test]
+	fn oversized_messages_rejected_as_too_large() {
+		let message = envelope(Topic([1, 2, 3, 4]), 0, now_secs() + 100);
+		let network = Network::new(::rlp::encode(&message).len() - 1, NullHandler);
+
+		match network.accept_batch(vec![message])[0] {
+			Err(AcceptError::TooLarge) => {},
+			ref other => panic!("unexpected {:?}", other),
+		}
+	}
+
+	#[test]
+	fn accept_batch_reports_each_envelope() {
+		let expiry = now_secs() + 100;
+		let (weak, strong) = weakest_and_strongest(expiry);
+		let pooled = by_work(expiry).into_iter()
+			.find(|e| work(e) > work(&weak) && work(e) < work(&strong))
+			.unwrap();
+
+		// fill the pool with a single message.
+		let size = ::rlp::encode(&pooled).len();
+		let network = Network::new(size, NullHandler);
+		assert!(network.accept_batch(vec![pooled])[0].is_ok());
+		assert!(network.messages.read().is_full());
+
+		let results = network.accept_batch(vec![
+			weak,
+			Envelope { ttl: 0, ..envelope(Topic([1, 2, 3, 4]), 0, expiry) },
+			strong.clone(),
+			strong,
+		]);
+
+		assert_eq!(results.len(), 4);
+		match results[0] { Err(AcceptError::InsufficientWork) => {}, ref other => panic!("unexpected {:?}", other) }
+		match results[1] { Err(AcceptError::Invalid(MessageError::ZeroTTL)) => {}, ref other => panic!("unexpected {:?}", other) }
+		assert!(results[2].is_ok());
+		match results[3] { Err(AcceptError::Known) => {}, ref other => panic!("unexpected {:?}", other) }
+	}
+
+	#[test]
+	fn empty_status_confirms_peer() {
+		let network = network_with_peer(1);
+		network.on_status(&1, UntrustedRlp::new(&::rlp::EMPTY_LIST_RLP)).unwrap();
+
+		let peers = network.peers.read();
+		let peer = peers[&1].lock();
+
+		assert!(peer.can_send_messages());
+		assert_eq!(peer.topic_filter, None);
+	}
+
+	#[test]
+	fn invalid_topics_dropped_individually() {
+		let valid = envelope(Topic([1, 2, 3, 4]), 0, now_secs() + 100);
+		let duplicated = Envelope {
+			topics: SmallVec::from_slice(&[Topic([1, 2, 3, 4]), Topic([1, 2, 3, 4])]),
+			..valid.clone()
+		};
+
+		let handler = Arc::new(RecordingHandler::default());
+		let network = Network::new(1024 * 1024, handler.clone());
+		add_confirmed_peer(&network, 1);
+
+		let packet = ::rlp::encode_list(&[duplicated.clone(), valid]);
+		network.on_messages(&1, UntrustedRlp::new(&packet)).unwrap();
+
+		assert_eq!(network.pool_status().message_count, 1);
+		assert_eq!(*handler.0.lock(), vec![
+			(duplicated.id(), format!("{:?}", AcceptError::Invalid(MessageError::InvalidTopics))),
+		]);
+	}
+
+	#[test]
+	fn invalid_envelopes_dropped_individually() {
+		let valid = envelope(Topic([1, 2, 3, 4]), 0, now_secs() + 100);
+		let zero_ttl = Envelope { ttl: 0, ..valid.clone() };
+		let lives_too_long = Envelope { ttl: valid.expiry, ..valid.clone() };
+
+		let handler = Arc::new(RecordingHandler::default());
+		let network = Network::new(1024 * 1024, handler.clone());
+		add_confirmed_peer(&network, 1);
+
+		let packet = ::rlp::encode_list(&[zero_ttl.clone(), valid, lives_too_long.clone()]);
+		network.on_messages(&1, UntrustedRlp::new(&packet)).unwrap();
+
+		assert_eq!(network.pool_status().message_count, 1);
+		assert_eq!(*handler.0.lock(), vec![
+			(zero_ttl.id(), format!("{:?}", AcceptError::Invalid(MessageError::ZeroTTL))),
+			(lives_too_long.id(), format!("{:?}", AcceptError::Invalid(MessageError::LivesTooLong))),
+		]);
+	}
+}
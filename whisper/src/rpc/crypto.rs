@@ -16,6 +16,8 @@
 
 //! Encryption schemes supported by RPC layer.
 
+use std::fmt;
+
 use bigint::hash::H256;
 use ethkey::{self, Public, Secret};
 use ring::aead::{self, AES_256_GCM, SealingKey, OpeningKey};
@@ -25,6 +27,40 @@ pub const AES_KEY_LEN: usize = 32;
 /// Length of AES nonce (IV)
 pub const AES_NONCE_LEN: usize = 12;
 
+/// Key material which is wiped from memory when dropped: an AES key, or a
+/// secp256k1 secret of the same length.
+///
+/// The `Debug` implementation never reveals the key bytes.
+#[derive(Clone)]
+pub struct ZeroizingKey([u8; AES_KEY_LEN]);
+
+impl ZeroizingKey {
+	/// Take ownership of raw key material.
+	pub fn new(key: [u8; AES_KEY_LEN]) -> Self {
+		ZeroizingKey(key)
+	}
+
+	/// Get a handle to the raw key material.
+	pub fn as_bytes(&self) -> &[u8; AES_KEY_LEN] {
+		&self.0
+	}
+}
+
+impl fmt::Debug for ZeroizingKey {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "<redacted>")
+	}
+}
+
+impl Drop for ZeroizingKey {
+	fn drop(&mut self) {
+		// volatile so the writes aren't optimized out as dead stores.
+		for byte in self.0.iter_mut() {
+			unsafe { ::std::ptr::write_volatile(byte, 0) }
+		}
+	}
+}
+
 // nonce used for encryption when broadcasting
 const BROADCAST_IV: [u8; AES_NONCE_LEN] = [0xff; AES_NONCE_LEN];
 
@@ -35,7 +71,7 @@ enum AesEncode {
 }
 
 enum EncryptionInner {
-	AES([u8; AES_KEY_LEN], [u8; AES_NONCE_LEN], AesEncode),
+	AES(ZeroizingKey, [u8; AES_NONCE_LEN], AesEncode),
 	ECIES(Public),
 }
 
@@ -57,7 +93,7 @@ impl EncryptionInstance {
 	///
 	/// If generating nonces with a secure RNG, limit uses such that
 	/// the chance of collision is negligible.
-	pub fn aes(key: [u8; AES_KEY_LEN], nonce: [u8; AES_NONCE_LEN]) -> Self {
+	pub fn aes(key: ZeroizingKey, nonce: [u8; AES_NONCE_LEN]) -> Self {
 		EncryptionInstance(EncryptionInner::AES(key, nonce, AesEncode::AppendedNonce))
 	}
 
@@ -65,7 +101,7 @@ impl EncryptionInstance {
 	///
 	/// Key reuse here is extremely dangerous. It should be randomly generated
 	/// with a secure RNG.
-	pub fn broadcast(key: [u8; AES_KEY_LEN], topics: Vec<H256>) -> Self {
+	pub fn broadcast(key: ZeroizingKey, topics: Vec<H256>) -> Self {
 		EncryptionInstance(EncryptionInner::AES(key, BROADCAST_IV, AesEncode::OnTopics(topics)))
 	}
 
@@ -73,7 +109,7 @@ impl EncryptionInstance {
 	pub fn encrypt(self, plain: &[u8]) -> Vec<u8> {
 		match self.0 {
 			EncryptionInner::AES(key, nonce, encode) => {
-				let sealing_key = SealingKey::new(&AES_256_GCM, &key)
+				let sealing_key = SealingKey::new(&AES_256_GCM, key.as_bytes())
 					.expect("key is of correct len; qed");
 
 				let encrypt_plain = move |buf: &mut Vec<u8>| {
@@ -105,10 +141,9 @@ impl EncryptionInstance {
 					}
 					AesEncode::OnTopics(topics) => {
 						let mut buf = Vec::new();
-						let key = H256(key);
 
 						for topic in topics {
-							buf.extend(&*(topic ^ key));
+							buf.extend(topic.iter().zip(key.as_bytes()).map(|(t, k)| t ^ k));
 						}
 
 						encrypt_plain(&mut buf);
@@ -125,13 +160,13 @@ impl EncryptionInstance {
 }
 
 enum AesExtract {
-	AppendedNonce([u8; AES_KEY_LEN]), // extract appended nonce.
+	AppendedNonce(ZeroizingKey), // extract appended nonce.
 	OnTopics(usize, usize, H256), // number of topics, index we know, topic we know.
 }
 
 enum DecryptionInner {
 	AES(AesExtract),
-	ECIES(Secret),
+	ECIES(ZeroizingKey),
 }
 
 /// Decryption instance good for single usage.
@@ -139,14 +174,14 @@ pub struct DecryptionInstance(DecryptionInner);
 
 impl DecryptionInstance {
 	/// ECIES decryption using secret key. Fails if invalid secret.
-	pub fn ecies(secret: Secret) -> Result<Self, &'static str> {
-		secret.check_validity().map_err(|_| "Invalid secret key")?;
+	pub fn ecies(secret: ZeroizingKey) -> Result<Self, &'static str> {
+		Secret::from_slice(secret.as_bytes()).check_validity().map_err(|_| "Invalid secret key")?;
 
 		Ok(DecryptionInstance(DecryptionInner::ECIES(secret)))
 	}
 
 	/// 256-bit AES GCM decryption with appended nonce.
-	pub fn aes(key: [u8; AES_KEY_LEN]) -> Self {
+	pub fn aes(key: ZeroizingKey) -> Self {
 		DecryptionInstance(DecryptionInner::AES(AesExtract::AppendedNonce(key)))
 	}
 
@@ -163,13 +198,13 @@ impl DecryptionInstance {
 		match self.0 {
 			DecryptionInner::AES(extract) => {
 				let decrypt = |
-					key: [u8; AES_KEY_LEN],
+					key: &ZeroizingKey,
 					nonce: [u8; AES_NONCE_LEN],
 					ciphertext: &[u8]
 				| {
 					if ciphertext.len() < AES_256_GCM.tag_len() { return None }
 
-					let opening_key = OpeningKey::new(&AES_256_GCM, &key)
+					let opening_key = OpeningKey::new(&AES_256_GCM, key.as_bytes())
 						.expect("key length is valid for mode; qed");
 
 					let mut buf = ciphertext.to_vec();
@@ -196,7 +231,7 @@ impl DecryptionInstance {
 						let nonce_offset = ciphertext.len() - AES_NONCE_LEN;
 
 						nonce.copy_from_slice(&ciphertext[nonce_offset..]);
-						decrypt(key, nonce, &ciphertext[..nonce_offset])
+						decrypt(&key, nonce, &ciphertext[..nonce_offset])
 					}
 					AesExtract::OnTopics(num_topics, known_index, known_topic) => {
 						if ciphertext.len() < num_topics * 32 { return None }
@@ -204,15 +239,17 @@ impl DecryptionInstance {
 						let mut salted_topic = H256::new();
 						salted_topic.copy_from_slice(&ciphertext[(known_index * 32)..][..32]);
 
-						let key = (salted_topic ^ known_topic).0;
+						let key = ZeroizingKey::new((salted_topic ^ known_topic).0);
 
 						let offset = num_topics * 32;
-						decrypt(key, BROADCAST_IV, &ciphertext[offset..])
+						decrypt(&key, BROADCAST_IV, &ciphertext[offset..])
 					}
 				}
 			}
-			DecryptionInner::ECIES(secret) => {
+			DecryptionInner::ECIES(key) => {
 				// secret is checked for validity, so only fails on invalid message.
+				// the unzeroed copy only lives for the duration of the call.
+				let secret = Secret::from_slice(key.as_bytes());
 				::ethcrypto::ecies::decrypt(&secret, &[], ciphertext).ok()
 			}
 		}
@@ -233,6 +270,14 @@ mod tests {
 		assert_eq!(::ring::aead::AES_256_GCM.nonce_len(), AES_NONCE_LEN);
 	}
 
+	#[test]
+	fn zeroizing_key_debug_is_redacted() {
+		let key = ZeroizingKey::new([0xab; AES_KEY_LEN]);
+		let formatted = format!("{:?}", key);
+
+		assert_eq!(formatted, "<redacted>");
+	}
+
 	#[test]
 	fn encrypt_asymmetric() {
 		use ethkey::{Generator, Random};
@@ -246,7 +291,7 @@ mod tests {
 				assert!(&ciphertext[..message.len()] != message)
 			}
 
-			let instance = DecryptionInstance::ecies(key_pair.secret().clone()).unwrap();
+			let instance = DecryptionInstance::ecies(ZeroizingKey::new((**key_pair.secret()).0)).unwrap();
 			let decrypted = instance.decrypt(&ciphertext).unwrap();
 
 			assert_eq!(message, &decrypted[..])
@@ -263,9 +308,9 @@ mod tests {
 
 		let mut rng = OsRng::new().unwrap();
 		let mut test_message = move |message: &[u8]| {
-			let key = rng.gen();
+			let key = ZeroizingKey::new(rng.gen());
 
-			let instance = EncryptionInstance::aes(key, rng.gen());
+			let instance = EncryptionInstance::aes(key.clone(), rng.gen());
 			let ciphertext = instance.encrypt(message);
 
 			if !message.is_empty() {
@@ -293,7 +338,7 @@ mod tests {
 			let all_topics = (0..5).map(|_| rng.gen()).collect::<Vec<_>>();
 			let known_idx = 2;
 			let known_topic = all_topics[2];
-			let key = rng.gen();
+			let key = ZeroizingKey::new(rng.gen());
 
 			let instance = EncryptionInstance::broadcast(key, all_topics);
 			let ciphertext = instance.encrypt(message);
@@ -16,9 +16,10 @@
 
 //! Abstraction over filters which works with polling and subscription.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{mpsc, Arc};
 use std::thread;
+use std::time::SystemTime;
 
 use bigint::hash::{H256, H512};
 use ethkey::Public;
@@ -26,7 +27,8 @@ use jsonrpc_macros::pubsub::{Subscriber, Sink};
 use parking_lot::{Mutex, RwLock};
 use rand::{Rng, OsRng};
 
-use message::{Message, Topic};
+use clock::{Clock, SystemClock};
+use message::{bloom_topics, Envelope, Message, Topic};
 use super::key_store::KeyStore;
 use super::types::{self, FilterItem, HexEncode};
 
@@ -51,6 +53,9 @@ enum FilterEntry {
 pub struct Manager {
 	key_store: Arc<RwLock<KeyStore>>,
 	filters: RwLock<HashMap<H256, FilterEntry>>,
+	decoys: Vec<Topic>,
+	bloom_listener: Mutex<Option<Box<Fn(Option<H512>) + Send + Sync>>>,
+	clock: Box<Clock>,
 	tx: Mutex<mpsc::Sender<Box<Fn() + Send>>>,
 	join: Option<thread::JoinHandle<()>>,
 }
@@ -59,6 +64,25 @@ impl Manager {
 	/// Create a new filter manager that will dispatch decryption tasks onto
 	/// the given thread pool.
 	pub fn new() -> ::std::io::Result<Self> {
+		Manager::with_decoy_topics(0)
+	}
+
+	/// Create a new filter manager which mixes the given number of random
+	/// decoy topics into the bloom advertised to peers.
+	///
+	/// Decoys are chosen once and kept for the lifetime of the manager, so
+	/// the advertised bloom can't be fingerprinted by its churn. More decoys
+	/// mean more irrelevant inbound traffic. Zero gives exact blooms.
+	pub fn with_decoy_topics(decoys: usize) -> ::std::io::Result<Self> {
+		Manager::with_clock(decoys, Box::new(SystemClock))
+	}
+
+	/// Create a new filter manager with the given number of decoy topics,
+	/// which takes the current time from the given clock.
+	pub fn with_clock(decoys: usize, clock: Box<Clock>) -> ::std::io::Result<Self> {
+		let mut rng = OsRng::new()?;
+		let decoys = (0..decoys).map(|_| Topic(rng.gen())).collect();
+
 		let (tx, rx) = mpsc::channel::<Box<Fn() + Send>>();
 		let join_handle = thread::Builder::new()
 			.name("Whisper Decryption Worker".to_string())
@@ -67,6 +91,9 @@ impl Manager {
 		Ok(Manager {
 			key_store: Arc::new(RwLock::new(KeyStore::new()?)),
 			filters: RwLock::new(HashMap::new()),
+			decoys: decoys,
+			bloom_listener: Mutex::new(None),
+			clock: clock,
 			tx: Mutex::new(tx),
 			join: Some(join_handle),
 		})
@@ -77,6 +104,51 @@ impl Manager {
 		self.key_store.clone()
 	}
 
+	/// Topic bloom to advertise to peers: the union of the blooms of all
+	/// filters' topics and any decoy topics.
+	///
+	/// Local matching never considers the decoys.
+	pub fn advertised_bloom(&self) -> H512 {
+		&self.local_bloom() | &bloom_topics(&self.decoys)
+	}
+
+	/// Set a function to be told the topic bloom to advertise whenever
+	/// filters are added or removed, and once immediately. It's given `None`
+	/// when there are no filters, asking peers for all messages.
+	pub fn set_bloom_listener<F>(&self, listener: F)
+		where F: Fn(Option<H512>) + Send + Sync + 'static
+	{
+		*self.bloom_listener.lock() = Some(Box::new(listener));
+		self.notify_bloom();
+	}
+
+	// tell the listener about the bloom to advertise. holding the listener
+	// lock keeps concurrent updates from arriving out of order.
+	fn notify_bloom(&self) {
+		let listener = self.bloom_listener.lock();
+		if let Some(ref listener) = *listener {
+			let bloom = if self.filters.read().is_empty() {
+				None
+			} else {
+				Some(self.advertised_bloom())
+			};
+
+			listener(bloom);
+		}
+	}
+
+	/// Union of the blooms of all filters' topics.
+	pub fn local_bloom(&self) -> H512 {
+		filters_bloom(&*self.filters.read())
+	}
+
+	/// Whether the envelope could match any local filter, judging only by
+	/// the local topic bloom. False positives are possible; false negatives
+	/// are not.
+	pub fn might_be_interesting(&self, envelope: &Envelope) -> bool {
+		might_match(&self.local_bloom(), envelope)
+	}
+
 	/// Get filter kind if it's known.
 	pub fn kind(&self, id: &H256) -> Option<Kind> {
 		self.filters.read().get(id).map(|filter| match *filter {
@@ -88,6 +160,7 @@ impl Manager {
 	/// Remove filter by ID.
 	pub fn remove(&self, id: &H256) {
 		self.filters.write().remove(id);
+		self.notify_bloom();
 	}
 
 	/// Add a new polled filter.
@@ -99,6 +172,7 @@ impl Manager {
 			.gen();
 
 		self.filters.write().insert(id, entry);
+		self.notify_bloom();
 		Ok(id)
 	}
 
@@ -115,6 +189,7 @@ impl Manager {
 			.map(move |sink| {
 				let entry = FilterEntry::Subscription(Arc::new(filter), sink);
 				self.filters.write().insert(id, entry);
+				self.notify_bloom();
 			})
 			.map_err(|_| "subscriber disconnected")
 	}
@@ -129,13 +204,46 @@ impl Manager {
 	}
 }
 
+fn filters_bloom(filters: &HashMap<H256, FilterEntry>) -> H512 {
+	let mut bloom = H512::default();
+	for entry in filters.values() {
+		let filter = match *entry {
+			FilterEntry::Poll(ref filter, _) | FilterEntry::Subscription(ref filter, _) => filter,
+		};
+
+		for &(_, ref topic_bloom, _) in &filter.topics {
+			bloom = &bloom | topic_bloom;
+		}
+	}
+
+	bloom
+}
+
+// a filter can only match an envelope carrying one of its topics, so some
+// topic of the envelope must lie within the bloom of all filters' topics.
+fn might_match(local_bloom: &H512, envelope: &Envelope) -> bool {
+	envelope.topics.iter().any(|topic| {
+		let bloom = topic.bloom();
+		&(&bloom & local_bloom) == &bloom
+	})
+}
+
 // machinery for attaching the manager to the network instance.
 impl ::net::MessageHandler for Arc<Manager> {
 	fn handle_messages(&self, messages: &[Message]) {
+		// retired keys are only dropped here, so they don't outlive their
+		// grace window by long.
+		let now = self.clock.now();
+		self.key_store.write().prune_grace_keys(now);
+
 		let filters = self.filters.read();
-		let filters_iter = filters
-			.values()
-			.flat_map(|filter| messages.iter().map(move |msg| (filter, msg))) ;
+		let filters = &*filters;
+		let local_bloom = filters_bloom(filters);
+
+		// skip messages no filter could match before trying each filter.
+		let filters_iter = messages.iter()
+			.filter(|msg| might_match(&local_bloom, msg.envelope()))
+			.flat_map(|msg| filters.values().map(move |filter| (filter, msg)));
 
 		for	(filter, message) in filters_iter {
 			// if the message matches any of the possible bloom filters,
@@ -152,6 +260,7 @@ impl ::net::MessageHandler for Arc<Manager> {
 						filter.handle_message(
 							&message,
 							&*key_store,
+							now,
 							|matched| buffer.lock().push(matched),
 						)
 					})).err().map(|x| x.0)
@@ -164,6 +273,7 @@ impl ::net::MessageHandler for Arc<Manager> {
 						filter.handle_message(
 							&message,
 							&*key_store,
+							now,
 							|matched| { let _ = sink.notify(Ok(matched)); },
 						)
 					})).err().map(|x| x.0)
@@ -180,17 +290,60 @@ impl ::net::MessageHandler for Arc<Manager> {
 
 impl Drop for Manager {
 	fn drop(&mut self) {
+		// hang up on the worker so it finishes before we join it.
+		*self.tx.lock() = mpsc::channel().0;
+
 		if let Some(guard) = self.join.take() {
 			let _ = guard.join();
 		}
 	}
 }
 
+// maximum number of (topic, sender) pairs a filter tracks sequence numbers for.
+const MAX_TRACKED_SEQUENCES: usize = 1024;
+
+type SequenceKey = (Topic, Public);
+
+// highest sequence number seen per topic and sender. once full, the
+// longest-tracked pairs are forgotten first.
+#[derive(Default)]
+struct Sequences {
+	last: HashMap<SequenceKey, u64>,
+	order: VecDeque<SequenceKey>,
+}
+
+impl Sequences {
+	// whether `sequence` is newer than the last one seen for every key.
+	// if so, it becomes the last seen for each of them.
+	fn note(&mut self, keys: &[SequenceKey], sequence: u64) -> bool {
+		let stale = keys.iter()
+			.any(|key| self.last.get(key).map_or(false, |&last| sequence <= last));
+
+		if stale { return false }
+
+		for key in keys {
+			if self.last.insert(key.clone(), sequence).is_none() {
+				self.order.push_back(key.clone());
+			}
+		}
+
+		while self.last.len() > MAX_TRACKED_SEQUENCES {
+			match self.order.pop_front() {
+				Some(key) => { self.last.remove(&key); }
+				None => break,
+			}
+		}
+
+		true
+	}
+}
+
 /// Filter incoming messages by critera.
 pub struct Filter {
 	topics: Vec<(Vec<u8>, H512, Topic)>,
 	from: Option<Public>,
 	decrypt_with: Option<H256>,
+	sequences: Mutex<Sequences>,
 }
 
 impl Filter {
@@ -214,6 +367,7 @@ impl Filter {
 			topics: topics,
 			from: params.from.map(|x| x.into_inner()),
 			decrypt_with: params.decrypt_with.map(|x| x.into_inner()),
+			sequences: Mutex::new(Sequences::default()),
 		})
 	}
 
@@ -227,11 +381,12 @@ impl Filter {
 		})
 	}
 
-	// handle a message that matches the bloom.
+	// handle a message that matches the bloom, received at `now`.
 	fn handle_message<F: Fn(FilterItem)>(
 		&self,
 		message: &Message,
 		store: &RwLock<KeyStore>,
+		now: SystemTime,
 		on_match: F,
 	) {
 		use rpc::crypto::DecryptionInstance;
@@ -249,26 +404,29 @@ impl Filter {
 
 		if matched_indices.is_empty() { return }
 
+		// symmetric identities may also have rotated-out keys to try.
 		let decrypt = match self.decrypt_with {
-			Some(ref id) => match store.read().decryption_instance(id) {
-				Some(d) => d,
-				None => {
+			Some(ref id) => {
+				let instances = store.read().decryption_instances(id, now);
+				if instances.is_empty() {
 					warn!(target: "whisper", "Filter attempted to decrypt with destroyed identity {}",
 						id);
 
 					return
 				}
-			},
+
+				instances
+			}
 			None => {
 				let known_idx = matched_indices[0];
 				let known_topic = H256(keccak256(&self.topics[0].0));
 
-				DecryptionInstance::broadcast(message.topics().len(), known_idx, known_topic)
-					.expect("known idx is within the range 0..message.topics.len(); qed")
+				vec![DecryptionInstance::broadcast(message.topics().len(), known_idx, known_topic)
+					.expect("known idx is within the range 0..message.topics.len(); qed")]
 			}
 		};
 
-		let decrypted = match decrypt.decrypt(message.data()) {
+		let decrypted = match decrypt.into_iter().filter_map(|d| d.decrypt(message.data())).next() {
 			Some(d) => d,
 			None => {
 				trace!(target: "whisper", "Failed to decrypt message with {} matching topics",
@@ -282,6 +440,22 @@ impl Filter {
 			Ok(decoded) => {
 				if decoded.from != self.from { return }
 
+				// reject replays of signed, sequenced messages within their
+				// TTL. anyone could forge an unsigned message's sequence
+				// number, so those are passed through unchecked.
+				if let (Some(sequence), Some(from)) = (decoded.sequence, decoded.from.as_ref()) {
+					let keys: Vec<_> = matched_indices.iter()
+						.map(|&i| (self.topics[i].2, from.clone()))
+						.collect();
+
+					if !self.sequences.lock().note(&keys, sequence) {
+						trace!(target: "whisper", "Dropping message with stale sequence number {}",
+							sequence);
+
+						return
+					}
+				}
+
 				let matched_topics = matched_indices
 					.into_iter()
 					.map(|i| self.topics[i].0.clone())
@@ -296,6 +470,7 @@ impl Filter {
 					timestamp: message.envelope().expiry - message.envelope().ttl,
 					payload: HexEncode(decoded.message.to_vec()),
 					padding: decoded.padding.map(|pad| HexEncode(pad.to_vec())),
+					sequence: decoded.sequence,
 				})
 			}
 			Err(reason) =>
@@ -310,6 +485,7 @@ mod tests {
 	use message::{CreateParams, Message, Topic};
 	use rpc::types::{FilterRequest, HexEncode};
 	use rpc::abridge_topic;
+	use ethkey::Secret;
 	use super::*;
 
 	#[test]
@@ -363,6 +539,109 @@ mod tests {
 		assert!(!filter.basic_matches(&message));
 	}
 
+	#[test]
+	fn decoys_only_in_advertised_bloom() {
+		let topics = vec![vec![1, 2, 3, 4]];
+		let abridged = abridge_topic(&topics[0]);
+
+		let manager = Manager::with_decoy_topics(3).unwrap();
+		assert_eq!(manager.decoys.len(), 3);
+
+		let filter = Filter::new(FilterRequest {
+			decrypt_with: Default::default(),
+			from: None,
+			topics: topics.into_iter().map(HexEncode).collect(),
+		}).unwrap();
+
+		let id = manager.insert_polled(filter).unwrap();
+		let advertised = manager.advertised_bloom();
+
+		assert!(advertised.contains(&abridged.bloom()));
+		for decoy in &manager.decoys {
+			assert!(advertised.contains(&decoy.bloom()));
+		}
+
+		let decoy_message = Message::create(CreateParams {
+			ttl: 100,
+			payload: vec![1, 3, 5, 7, 9],
+			topics: manager.decoys.clone(),
+			work: 0,
+		}).unwrap();
+
+		let filters = manager.filters.read();
+		match filters[&id] {
+			FilterEntry::Poll(ref filter, _) => assert!(!filter.basic_matches(&decoy_message)),
+			FilterEntry::Subscription(_, _) => panic!("inserted a polled filter; qed"),
+		}
+	}
+
+	#[test]
+	fn no_decoys_gives_exact_bloom() {
+		let topics = vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]];
+		let abridged_topics: Vec<_> = topics.iter().map(|x| abridge_topic(&x)).collect();
+
+		let manager = Manager::new().unwrap();
+		manager.insert_polled(Filter::new(FilterRequest {
+			decrypt_with: Default::default(),
+			from: None,
+			topics: topics.into_iter().map(HexEncode).collect(),
+		}).unwrap()).unwrap();
+
+		assert_eq!(manager.advertised_bloom(), ::message::bloom_topics(&abridged_topics));
+	}
+
+	#[test]
+	fn bloom_listener_follows_filters() {
+		let manager = Manager::with_decoy_topics(2).unwrap();
+		let blooms = Arc::new(Mutex::new(Vec::new()));
+
+		let recorded = blooms.clone();
+		manager.set_bloom_listener(move |bloom| recorded.lock().push(bloom));
+
+		let id = manager.insert_polled(Filter::new(FilterRequest {
+			decrypt_with: Default::default(),
+			from: None,
+			topics: vec![HexEncode(vec![1, 2, 3, 4])],
+		}).unwrap()).unwrap();
+		let advertised = manager.advertised_bloom();
+
+		manager.remove(&id);
+
+		assert_eq!(*blooms.lock(), vec![None, Some(advertised), None]);
+	}
+
+	#[test]
+	fn might_be_interesting_by_local_bloom() {
+		let topics = vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]];
+		let abridged_topics: Vec<_> = topics.iter().map(|x| abridge_topic(&x)).collect();
+
+		let manager = Manager::with_decoy_topics(4).unwrap();
+		manager.insert_polled(Filter::new(FilterRequest {
+			decrypt_with: Default::default(),
+			from: None,
+			topics: topics.into_iter().map(HexEncode).collect(),
+		}).unwrap()).unwrap();
+
+		let envelope_with_topics = |topics| Message::create(CreateParams {
+			ttl: 100,
+			payload: vec![1, 3, 5, 7, 9],
+			topics: topics,
+			work: 0,
+		}).unwrap().envelope().clone();
+
+		for topic in &abridged_topics {
+			let envelope = envelope_with_topics(vec![Topic([9, 9, 9, 9]), *topic]);
+			assert!(manager.might_be_interesting(&envelope));
+		}
+
+		let false_positives = (0..1000u32)
+			.map(|i| Topic([0xaa, 0xbb, (i >> 8) as u8, i as u8]))
+			.filter(|topic| manager.might_be_interesting(&envelope_with_topics(vec![*topic])))
+			.count();
+
+		assert!(false_positives < 10);
+	}
+
 	#[test]
 	fn decrypt_and_decode() {
 		use rpc::payload::{self, EncodeParams};
@@ -383,7 +662,9 @@ mod tests {
 		let payload = payload::encode(EncodeParams {
 			message: &[1, 2, 3],
 			padding: Some(&[4, 5, 4, 5]),
-			sign_with: Some(signing_pair.secret().unwrap())
+			sign_with: Some(&Secret::from_slice(signing_pair.secret().unwrap().as_bytes())),
+			pad_to: None,
+			sequence: None,
 		}).unwrap();
 
 		let encrypted = encryption_instance.encrypt(&payload);
@@ -414,9 +695,85 @@ mod tests {
 		let items = ::std::cell::Cell::new(0);
 		let on_match = |_| { items.set(items.get() + 1); };
 
-		filter.handle_message(&message, &store, &on_match);
-		filter.handle_message(&message2, &store, &on_match);
+		filter.handle_message(&message, &store, SystemTime::now(), &on_match);
+		filter.handle_message(&message2, &store, SystemTime::now(), &on_match);
 
 		assert_eq!(items.get(), 1);
 	}
+
+	#[test]
+	fn rejects_stale_sequence() {
+		use rpc::payload::{self, EncodeParams};
+		use rpc::key_store::{Key, KeyStore};
+
+		let topics = vec![vec![1, 2, 3, 4]];
+		let abridged_topics: Vec<_> = topics.iter().map(|x| abridge_topic(&x)).collect();
+
+		let mut store = KeyStore::new().unwrap();
+		let signing_pair = Key::new_asymmetric(store.rng());
+		let encrypting_key = Key::new_symmetric(store.rng());
+
+		let signing_secret = Secret::from_slice(signing_pair.secret().unwrap().as_bytes());
+		let decrypt_id = store.insert(encrypting_key);
+		let store = ::parking_lot::RwLock::new(store);
+
+		let message_with_sequence = |sequence, signed| {
+			let encryption_instance = store.read().encryption_instance(&decrypt_id).unwrap();
+			let payload = payload::encode(EncodeParams {
+				message: &[1, 2, 3],
+				sign_with: if signed { Some(&signing_secret) } else { None },
+				sequence: Some(sequence),
+				..Default::default()
+			}).unwrap();
+
+			Message::create(CreateParams {
+				ttl: 100,
+				payload: encryption_instance.encrypt(&payload),
+				topics: abridged_topics.clone(),
+				work: 0,
+			}).unwrap()
+		};
+
+		let filter = |from: Option<&Public>| Filter::new(FilterRequest {
+			decrypt_with: Some(HexEncode(decrypt_id)),
+			from: from.map(|from| HexEncode(from.clone())),
+			topics: topics.iter().cloned().map(HexEncode).collect(),
+		}).unwrap();
+
+		let items = ::std::cell::RefCell::new(Vec::new());
+		let on_match = |item: FilterItem| items.borrow_mut().push(item.sequence);
+
+		let signed_filter = filter(signing_pair.public());
+		let first = message_with_sequence(1, true);
+		signed_filter.handle_message(&first, &store, SystemTime::now(), &on_match);
+		signed_filter.handle_message(&message_with_sequence(2, true), &store, SystemTime::now(), &on_match);
+
+		// replaying the first envelope, or an older sequence, is rejected.
+		signed_filter.handle_message(&first, &store, SystemTime::now(), &on_match);
+		signed_filter.handle_message(&message_with_sequence(2, true), &store, SystemTime::now(), &on_match);
+
+		assert_eq!(*items.borrow(), vec![Some(1), Some(2)]);
+
+		// unsigned sequence numbers aren't enforced.
+		items.borrow_mut().clear();
+		let unsigned_filter = filter(None);
+		let first = message_with_sequence(1, false);
+		unsigned_filter.handle_message(&first, &store, SystemTime::now(), &on_match);
+		unsigned_filter.handle_message(&first, &store, SystemTime::now(), &on_match);
+
+		assert_eq!(*items.borrow(), vec![Some(1), Some(1)]);
+	}
+
+	#[test]
+	fn sequences_are_bounded() {
+		let mut sequences = Sequences::default();
+		for i in 0..(MAX_TRACKED_SEQUENCES as u32 + 10) {
+			let topic = Topic([(i >> 24) as u8, (i >> 16) as u8, (i >> 8) as u8, i as u8]);
+			assert!(sequences.note(&[(topic, Public::default())], 5));
+			assert!(!sequences.note(&[(topic, Public::default())], 5));
+		}
+
+		assert_eq!(sequences.last.len(), MAX_TRACKED_SEQUENCES);
+		assert_eq!(sequences.order.len(), MAX_TRACKED_SEQUENCES);
+	}
 }
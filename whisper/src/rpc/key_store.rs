@@ -20,62 +20,73 @@
 //! Symmetric encryption is done via AES-256 in GCM mode.
 
 use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
 use bigint::hash::H256;
 use ethkey::{KeyPair, Public, Secret};
 use rand::{Rng, OsRng};
 use ring::error::Unspecified;
 
-use rpc::crypto::{AES_KEY_LEN, EncryptionInstance, DecryptionInstance};
+use rpc::crypto::{AES_KEY_LEN, EncryptionInstance, DecryptionInstance, ZeroizingKey};
 
 /// A symmetric or asymmetric key used for encryption, decryption, and signing
-/// of payloads.
+/// of payloads. Secret key material is zeroed on drop.
 pub enum Key {
-	/// ECIES key pair for Secp2561k curve. Suitable for encryption, decryption,
-	/// and signing.
-	Asymmetric(KeyPair),
+	/// ECIES key pair for Secp2561k curve, as secret and public key. Suitable
+	/// for encryption, decryption, and signing.
+	Asymmetric(ZeroizingKey, Public),
 	/// AES-256 GCM mode. Suitable for encryption, decryption, but not signing.
-	Symmetric([u8; AES_KEY_LEN]),
+	Symmetric(ZeroizingKey),
 }
 
 impl Key {
 	/// Generate a random asymmetric key with the given cryptographic RNG.
 	pub fn new_asymmetric(rng: &mut OsRng) -> Self {
 		match ::ethkey::Generator::generate(rng) {
-			Ok(pair) => Key::Asymmetric(pair),
+			Ok(pair) => Key::from_pair(pair),
 			Err(void) => match void {},
 		}
 	}
 
+	// take the secret out of a key pair. secp256k1 secrets are as long as
+	// AES-256 keys.
+	fn from_pair(pair: KeyPair) -> Self {
+		let mut secret = [0u8; AES_KEY_LEN];
+		secret.copy_from_slice(&pair.secret()[..]);
+
+		Key::Asymmetric(ZeroizingKey::new(secret), pair.public().clone())
+	}
+
 	/// Generate a random symmetric key with the given cryptographic RNG.
 	pub fn new_symmetric(rng: &mut OsRng) -> Self {
-		Key::Symmetric(rng.gen())
+		Key::Symmetric(ZeroizingKey::new(rng.gen()))
 	}
 
 	/// From secret asymmetric key. Fails if secret is invalid.
 	pub fn from_secret(secret: Secret) -> Result<Self, Unspecified> {
 		KeyPair::from_secret(secret)
-			.map(Key::Asymmetric)
+			.map(Key::from_pair)
 			.map_err(|_| Unspecified)
 	}
 
 	/// From raw symmetric key.
 	pub fn from_raw_symmetric(key: [u8; AES_KEY_LEN]) -> Self {
-		Key::Symmetric(key)
+		Key::Symmetric(ZeroizingKey::new(key))
 	}
 
 	/// Get a handle to the public key if this is an asymmetric key.
 	pub fn public(&self) -> Option<&Public> {
 		match *self {
-			Key::Asymmetric(ref pair) => Some(pair.public()),
+			Key::Asymmetric(_, ref public) => Some(public),
 			Key::Symmetric(_) => None,
 		}
 	}
 
 	/// Get a handle to the secret key if this is an asymmetric key.
-	pub fn secret(&self) -> Option<&Secret> {
+	pub fn secret(&self) -> Option<&ZeroizingKey> {
 		match *self {
-			Key::Asymmetric(ref pair) => Some(pair.secret()),
+			Key::Asymmetric(ref secret, _) => Some(secret),
 			Key::Symmetric(_) => None,
 		}
 	}
@@ -83,16 +94,33 @@ impl Key {
 	/// Get a handle to the symmetric key.
 	pub fn symmetric(&self) -> Option<&[u8; AES_KEY_LEN]>  {
 		match *self {
-			Key::Asymmetric(_) => None,
-			Key::Symmetric(ref key) => Some(key),
+			Key::Asymmetric(_, _) => None,
+			Key::Symmetric(ref key) => Some(key.as_bytes()),
+		}
+	}
+
+	// human-readable kind of key, for redacted output.
+	fn kind(&self) -> &'static str {
+		match *self {
+			Key::Asymmetric(_, _) => "key pair",
+			Key::Symmetric(_) => "sym key",
 		}
 	}
 }
 
+impl fmt::Debug for Key {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "<{}>", self.kind())
+	}
+}
+
 /// Key store.
 pub struct KeyStore {
 	rng: OsRng,
 	identities: HashMap<H256, Key>,
+	// keys replaced by rotation, with the time they stop being used for
+	// decryption. most recently retired last.
+	grace_keys: HashMap<H256, Vec<(ZeroizingKey, SystemTime)>>,
 }
 
 impl KeyStore {
@@ -102,6 +130,7 @@ impl KeyStore {
 		Ok(KeyStore {
 			rng: OsRng::new()?,
 			identities: HashMap::new(),
+			grace_keys: HashMap::new(),
 		})
 	}
 
@@ -124,7 +153,7 @@ impl KeyStore {
 	}
 
 	/// Get asymmetric ID's secret key.
-	pub fn secret<'a>(&'a self, id: &H256) -> Option<&'a Secret> {
+	pub fn secret<'a>(&'a self, id: &H256) -> Option<&'a ZeroizingKey> {
 		self.get(id).and_then(Key::secret)
 	}
 
@@ -136,7 +165,7 @@ impl KeyStore {
 	/// Get encryption instance for identity.
 	pub fn encryption_instance(&self, id: &H256) -> Result<EncryptionInstance, &'static str> {
 		self.get(id).ok_or("no such identity").and_then(|key| match *key {
-			Key::Asymmetric(ref pair) => EncryptionInstance::ecies(pair.public().clone())
+			Key::Asymmetric(_, ref public) => EncryptionInstance::ecies(public.clone())
 				.map_err(|_| "could not create encryption instance for id"),
 			Key::Symmetric(ref key) =>
 				 OsRng::new()
@@ -149,12 +178,55 @@ impl KeyStore {
 	/// If the identity is known, always succeeds.
 	pub fn decryption_instance(&self, id: &H256) -> Option<DecryptionInstance> {
 		self.get(id).map(|key| match *key {
-			Key::Asymmetric(ref pair) => DecryptionInstance::ecies(pair.secret().clone())
+			Key::Asymmetric(ref secret, _) => DecryptionInstance::ecies(secret.clone())
 				.expect("all keys stored are valid; qed"),
 			Key::Symmetric(ref key) => DecryptionInstance::aes(key.clone()),
 		})
 	}
 
+	/// Get decryption instances for identity: the current key first, followed
+	/// by any rotated-out symmetric keys still in their grace window at `now`.
+	/// Empty if the identity is unknown.
+	pub fn decryption_instances(&self, id: &H256, now: SystemTime) -> Vec<DecryptionInstance> {
+		let grace_keys = self.grace_keys.get(id).into_iter()
+			.flat_map(|keys| keys.iter().rev())
+			.filter(|&&(_, retire_at)| now < retire_at)
+			.map(|&(ref key, _)| DecryptionInstance::aes(key.clone()));
+
+		self.decryption_instance(id).into_iter().chain(grace_keys).collect()
+	}
+
+	/// Replace the symmetric key of an identity, keeping the old key usable
+	/// for decryption for `grace` from `now` so in-flight messages can still
+	/// be read. Fails if the identity is unknown or asymmetric.
+	pub fn rotate_symmetric(
+		&mut self,
+		id: &H256,
+		new_key: [u8; AES_KEY_LEN],
+		grace: Duration,
+		now: SystemTime,
+	) -> bool {
+		let old_key = match self.identities.get_mut(id) {
+			Some(&mut Key::Symmetric(ref mut key)) =>
+				::std::mem::replace(key, ZeroizingKey::new(new_key)),
+			_ => return false,
+		};
+
+		self.prune_grace_keys(now);
+		self.grace_keys.entry(*id).or_insert_with(Vec::new).push((old_key, now + grace));
+
+		true
+	}
+
+	/// Drop rotated-out keys whose grace window has ended at `now`.
+	pub fn prune_grace_keys(&mut self, now: SystemTime) {
+		for keys in self.grace_keys.values_mut() {
+			keys.retain(|&(_, retire_at)| now < retire_at);
+		}
+
+		self.grace_keys.retain(|_, keys| !keys.is_empty());
+	}
+
 	/// Whether the store contains a key by this ID.
 	pub fn contains(&self, id: &H256) -> bool {
 		self.identities.contains_key(id)
@@ -162,6 +234,7 @@ impl KeyStore {
 
 	/// Remove a key by ID.
 	pub fn remove(&mut self, id: &H256) -> bool {
+		self.grace_keys.remove(id);
 		self.identities.remove(id).is_some()
 	}
 
@@ -171,6 +244,18 @@ impl KeyStore {
 	}
 }
 
+// only shows identities, never key material.
+impl fmt::Debug for KeyStore {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let mut list = f.debug_list();
+		for (id, key) in &self.identities {
+			list.entry(&format_args!("<{} 0x{}>", key.kind(), id));
+		}
+
+		list.finish()
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -181,6 +266,26 @@ mod tests {
 		assert!(Key::from_secret(bad_secret).is_err());
 	}
 
+	#[test]
+	fn debug_output_has_no_key_bytes() {
+		let mut store = KeyStore::new().unwrap();
+		let sym_id = store.insert(Key::from_raw_symmetric([0xab; AES_KEY_LEN]));
+
+		let pair = Key::new_asymmetric(store.rng());
+		let secret = H256(*pair.secret().unwrap().as_bytes());
+		let pair_id = store.insert(pair);
+
+		// identities are abbreviated, e.g. `<sym key 0x12ab…cdef>`.
+		let formatted = format!("{:?}", store);
+		assert!(formatted.contains(&format!("<sym key 0x{}>", sym_id)));
+		assert!(formatted.contains(&format!("<key pair 0x{}>", pair_id)));
+		assert!(formatted.contains('…'));
+		assert!(!formatted.contains(&format!("{:?}", secret)));
+
+		assert_eq!(format!("{:?}", store.get(&sym_id).unwrap()), "<sym key>");
+		assert_eq!(format!("{:?}", store.get(&pair_id).unwrap()), "<key pair>");
+	}
+
 	#[test]
 	fn generated_key_should_exist() {
 		let mut store = KeyStore::new().unwrap();
@@ -194,4 +299,63 @@ mod tests {
 		assert!(store.contains(&id));
 		assert!(store.get(&id).is_some());
 	}
+
+	#[test]
+	fn rotated_key_decrypts_during_grace() {
+		use rpc::crypto::AES_NONCE_LEN;
+
+		let mut store = KeyStore::new().unwrap();
+		let id = store.insert(Key::from_raw_symmetric([1; AES_KEY_LEN]));
+
+		let encrypt = |key| EncryptionInstance::aes(ZeroizingKey::new(key), [0; AES_NONCE_LEN])
+			.encrypt(&[1, 2, 3]);
+		let old_ciphertext = encrypt([1; AES_KEY_LEN]);
+		let new_ciphertext = encrypt([2; AES_KEY_LEN]);
+
+		let now = SystemTime::now();
+		let grace = Duration::from_secs(60);
+
+		assert!(store.rotate_symmetric(&id, [2; AES_KEY_LEN], grace, now));
+		assert_eq!(store.symmetric(&id), Some(&[2; AES_KEY_LEN]));
+
+		let decrypt = |ciphertext: &[u8], at| store.decryption_instances(&id, at)
+			.into_iter()
+			.filter_map(|instance| instance.decrypt(ciphertext))
+			.next();
+
+		assert_eq!(decrypt(&new_ciphertext, now), Some(vec![1, 2, 3]));
+		assert_eq!(decrypt(&old_ciphertext, now), Some(vec![1, 2, 3]));
+
+		let retired = now + grace;
+		assert_eq!(decrypt(&new_ciphertext, retired), Some(vec![1, 2, 3]));
+		assert_eq!(decrypt(&old_ciphertext, retired), None);
+	}
+
+	#[test]
+	fn prunes_retired_grace_keys() {
+		let mut store = KeyStore::new().unwrap();
+		let id = store.insert(Key::from_raw_symmetric([1; AES_KEY_LEN]));
+
+		let now = SystemTime::now();
+		let grace = Duration::from_secs(60);
+		assert!(store.rotate_symmetric(&id, [2; AES_KEY_LEN], grace, now));
+
+		store.prune_grace_keys(now + grace - Duration::from_secs(1));
+		assert_eq!(store.grace_keys[&id].len(), 1);
+
+		store.prune_grace_keys(now + grace);
+		assert!(store.grace_keys.is_empty());
+		assert_eq!(store.decryption_instances(&id, now).len(), 1);
+	}
+
+	#[test]
+	fn only_rotates_symmetric_keys() {
+		let mut store = KeyStore::new().unwrap();
+		let key_pair = Key::new_asymmetric(store.rng());
+		let id = store.insert(key_pair);
+
+		let now = SystemTime::now();
+		assert!(!store.rotate_symmetric(&id, [2; AES_KEY_LEN], Duration::from_secs(60), now));
+		assert!(!store.rotate_symmetric(&H256::zero(), [2; AES_KEY_LEN], Duration::from_secs(60), now));
+	}
 }
@@ -28,6 +28,7 @@ use jsonrpc_pubsub::{Session, PubSubMetadata, SubscriptionId};
 use jsonrpc_macros::pubsub;
 
 use bigint::hash::H256;
+use ethkey::Secret;
 use parking_lot::RwLock;
 
 use self::filter::Filter;
@@ -109,8 +110,9 @@ build_rpc_trait! {
 		fn remove_key(&self, types::Identity) -> Result<bool, Error>;
 
 		/// Post a message to the network with given parameters.
+		/// Returns the ID of the envelope sent.
 		#[rpc(name = "shh_post")]
-		fn post(&self, types::PostRequest) -> Result<bool, Error>;
+		fn post(&self, types::PostRequest) -> Result<types::EnvelopeId, Error>;
 
 		/// Create a new polled filter.
 		#[rpc(name = "shh_newMessageFilter")]
@@ -147,8 +149,8 @@ build_rpc_trait! {
 /// Something which can send messages to the network.
 pub trait PoolHandle: Send + Sync {
 	/// Give message to the whisper network for relay.
-	/// Returns false if PoW too low.
-	fn relay(&self, message: Message) -> bool;
+	/// Returns the envelope ID, or `None` if PoW too low.
+	fn relay(&self, message: Message) -> Option<H256>;
 
 	/// Number of messages and memory used by resident messages.
 	fn pool_status(&self) -> ::net::PoolStatus;
@@ -172,6 +174,7 @@ pub struct WhisperClient<P, M = Meta> {
 	store: Arc<RwLock<KeyStore>>,
 	pool: P,
 	filter_manager: Arc<filter::Manager>,
+	pad_to: Option<usize>,
 	_meta: ::std::marker::PhantomData<M>,
 }
 
@@ -189,10 +192,22 @@ impl<P, M> WhisperClient<P, M> {
 			store: filter_manager.key_store(),
 			pool: pool,
 			filter_manager: filter_manager,
+			pad_to: None,
 			_meta: ::std::marker::PhantomData,
 		}
 	}
 
+	/// Pad every posted payload to a multiple of `block_size` bytes, so that
+	/// envelope sizes reveal less about the length of the message.
+	pub fn with_padding_block(mut self, block_size: usize) -> Self {
+		self.pad_to = match block_size {
+			0 => None,
+			n => Some(n),
+		};
+
+		self
+	}
+
 	fn delete_filter_kind(&self, id: H256, kind: filter::Kind) -> bool {
 		match self.filter_manager.kind(&id) {
 			Some(k) if k == kind => {
@@ -253,7 +268,7 @@ impl<P: PoolHandle + 'static, M: Send + Sync + 'static> Whisper for WhisperClien
 
 	fn get_private(&self, id: types::Identity) -> Result<types::Private, Error> {
 		self.store.read().secret(&id.into_inner())
-			.map(|x| (&**x).clone())
+			.map(|x| H256(*x.as_bytes()))
 			.map(HexEncode)
 			.ok_or_else(|| whisper_error("Unknown identity"))
 	}
@@ -270,8 +285,8 @@ impl<P: PoolHandle + 'static, M: Send + Sync + 'static> Whisper for WhisperClien
 		Ok(self.store.write().remove(&id.into_inner()))
 	}
 
-	fn post(&self, req: types::PostRequest) -> Result<bool, Error> {
-		use self::crypto::EncryptionInstance;
+	fn post(&self, req: types::PostRequest) -> Result<types::EnvelopeId, Error> {
+		use self::crypto::{EncryptionInstance, ZeroizingKey};
 
 		let encryption = match req.to {
 			Some(types::Receiver::Public(public)) => EncryptionInstance::ecies(public.into_inner())
@@ -286,7 +301,7 @@ impl<P: PoolHandle + 'static, M: Send + Sync + 'static> Whisper for WhisperClien
 				let mut rng = OsRng::new()
 					.map_err(|_| whisper_error("unable to acquire secure randomness"))?;
 
-				let key = rng.gen();
+				let key = ZeroizingKey::new(rng.gen());
 				if req.topics.is_empty() {
 					return Err(whisper_error("must supply at least one topic for broadcast message"));
 				}
@@ -302,7 +317,7 @@ impl<P: PoolHandle + 'static, M: Send + Sync + 'static> Whisper for WhisperClien
 			Some(from) => {
 				Some(
 					self.store.read().secret(&from.into_inner())
-						.cloned()
+						.map(|x| Secret::from_slice(x.as_bytes()))
 						.ok_or_else(|| whisper_error("Unknown identity `from`"))?
 				)
 			}
@@ -314,6 +329,8 @@ impl<P: PoolHandle + 'static, M: Send + Sync + 'static> Whisper for WhisperClien
 				message: &req.payload.into_inner(),
 				padding: req.padding.map(|p| p.into_inner()).as_ref().map(|x| &x[..]),
 				sign_with: sign_with.as_ref(),
+				pad_to: self.pad_to,
+				sequence: req.sequence,
 			}).map_err(whisper_error)?;
 
 			encryption.encrypt(&payload)
@@ -328,13 +345,11 @@ impl<P: PoolHandle + 'static, M: Send + Sync + 'static> Whisper for WhisperClien
 			payload: encrypted,
 			topics: req.topics.into_iter().map(|x| abridge_topic(&x.into_inner())).collect(),
 			work: req.priority,
-		}).map_err(|_| whisper_error("Empty topics"))?;
+		}).map_err(|e| whisper_error(format!("{}", e)))?;
 
-		if !self.pool.relay(message) {
-			Err(whisper_error("PoW too low to compete with other messages"))
-		} else {
-			Ok(true)
-		}
+		self.pool.relay(message)
+			.map(HexEncode)
+			.ok_or_else(|| whisper_error("PoW too low to compete with other messages"))
 	}
 
 	fn new_filter(&self, req: types::FilterRequest) -> Result<types::Identity, Error> {
@@ -389,3 +404,61 @@ impl<P: PoolHandle + 'static, M: Send + Sync + PubSubMetadata> WhisperPubSub for
 		res.map_err(whisper_error)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use parking_lot::Mutex;
+
+	use super::*;
+	use super::types::{PostRequest, Receiver};
+
+	// keeps relayed messages instead of sending them anywhere.
+	#[derive(Default)]
+	struct CapturingPool(Mutex<Vec<Message>>);
+
+	impl PoolHandle for Arc<CapturingPool> {
+		fn relay(&self, message: Message) -> Option<H256> {
+			let id = message.hash().clone();
+			self.0.lock().push(message);
+			Some(id)
+		}
+
+		fn pool_status(&self) -> ::net::PoolStatus {
+			::net::PoolStatus { required_pow: None, message_count: 0, cumulative_size: 0, target_size: 0 }
+		}
+	}
+
+	// post a short message to a symmetric key and get back the length of
+	// the plaintext payload which was sent.
+	fn posted_payload_len(block_size: usize) -> usize {
+		let pool = Arc::new(CapturingPool::default());
+		let manager = Arc::new(filter::Manager::new().unwrap());
+		let client: WhisperClient<_, Meta> = WhisperClient::new(pool.clone(), manager)
+			.with_padding_block(block_size);
+
+		let key_id = client.new_sym_key().unwrap().into_inner();
+		client.post(PostRequest {
+			to: Some(Receiver::Identity(HexEncode(key_id))),
+			from: None,
+			topics: vec![HexEncode(vec![1, 2, 3, 4])],
+			payload: HexEncode(vec![1, 2, 3]),
+			padding: None,
+			priority: 0,
+			ttl: 100,
+			sequence: None,
+		}).unwrap();
+
+		let message = pool.0.lock().pop().unwrap();
+		let decryption = client.store.read().decryption_instance(&key_id).unwrap();
+
+		decryption.decrypt(message.data()).unwrap().len()
+	}
+
+	#[test]
+	fn post_pads_to_block_size() {
+		assert_eq!(posted_payload_len(256), 256);
+		assert_eq!(posted_payload_len(64), 64);
+		assert!(posted_payload_len(0) < 64);
+	}
+}
@@ -22,14 +22,21 @@
 //! payload size: 0..4 bytes, BE, determined by flags.
 //! optional padding: byte array up to 2^24 bytes in length. encoded in payload size.
 //! optional signature: 65 bytes (r, s, v)
+//! optional sequence number: 8 bytes, BE.
 //!
 //! payload: byte array of length of arbitrary size.
 //!
 //! flag bits used:
 //!   0, 1 => how many bytes indicate padding length (up to 3)
 //!   2 => whether signature is present
+//!   3 => whether sequence number is present
 //!
-//! padding is used to mask information about size of message.
+//! when a sequence number is present, the signature covers it as well as
+//! the payload.
+//!
+//! padding is used to mask information about size of message. when `pad_to`
+//! is given, padding is extended so the whole plaintext is a multiple of that
+//! many bytes.
 //!
 //! AES-256-GCM will append 12 bytes of metadata to the front of the message.
 
@@ -40,6 +47,8 @@ use tiny_keccak::keccak256;
 
 const SIGNATURE_LEN: usize = 65;
 
+const SEQUENCE_LEN: usize = 8;
+
 const STANDARD_PAYLOAD_VERSION: u8 = 1;
 
 bitflags! {
@@ -47,6 +56,7 @@ bitflags! {
 		const FLAG_PAD_LEN_HIGH = 0b10000000;
 		const FLAG_PAD_LEN_LOW  = 0b01000000;
 		const FLAG_SIGNED       = 0b00100000;
+		const FLAG_SEQUENCE     = 0b00010000;
 	}
 }
 
@@ -73,6 +83,41 @@ fn num_padding_length_bytes(padding_len: usize) -> Option<usize> {
 	}
 }
 
+// total padding length, at least `min_padding`, making a plaintext with
+// `unpadded_len` other bytes a multiple of `block_size` once the padding
+// length prefix is included. `None` if too large.
+fn padding_to_block(unpadded_len: usize, min_padding: usize, block_size: usize) -> Option<usize> {
+	// try each size of length prefix in turn, smallest first.
+	(0..4).filter_map(|len_bytes| {
+		let lower = match len_bytes {
+			0 => min_padding,
+			n => ::std::cmp::max(min_padding, 1 << (8 * (n - 1))),
+		};
+
+		let used = unpadded_len + len_bytes + lower;
+		let padding_len = lower + (block_size - used % block_size) % block_size;
+
+		match num_padding_length_bytes(padding_len) {
+			Some(n) if n == len_bytes => Some(padding_len),
+			_ => None,
+		}
+	}).next()
+}
+
+// hash signed by the sender: covers the sequence number if there is one.
+fn signing_hash(message: &[u8], sequence: Option<u64>) -> H256 {
+	match sequence {
+		None => H256(keccak256(message)),
+		Some(sequence) => {
+			let mut buf = vec![0; SEQUENCE_LEN];
+			BigEndian::write_u64(&mut buf, sequence);
+			buf.extend(message);
+
+			H256(keccak256(&buf))
+		}
+	}
+}
+
 /// Parameters for encoding a standard payload.
 pub struct EncodeParams<'a> {
 	/// Message to encode.
@@ -81,6 +126,10 @@ pub struct EncodeParams<'a> {
 	pub padding: Option<&'a [u8]>,
 	/// Private key to sign with.
 	pub sign_with: Option<&'a Secret>,
+	/// Extend padding so the encoded payload is a multiple of this many bytes.
+	pub pad_to: Option<usize>,
+	/// Sequence number, allowing receivers to reject replays.
+	pub sequence: Option<u64>,
 }
 
 impl<'a> Default for EncodeParams<'a> {
@@ -89,6 +138,8 @@ impl<'a> Default for EncodeParams<'a> {
 			message: &[],
 			padding: None,
 			sign_with: None,
+			pad_to: None,
+			sequence: None,
 		}
 	}
 }
@@ -101,18 +152,16 @@ pub struct Decoded<'a> {
 	pub padding: Option<&'a [u8]>,
 	/// Recovered signature.
 	pub from: Option<Public>,
+	/// Sequence number.
+	pub sequence: Option<u64>,
 }
 
 /// Encode using provided parameters.
 pub fn encode(params: EncodeParams) -> Result<Vec<u8>, &'static str> {
 	const VEC_WRITE_INFALLIBLE: &'static str = "writing to a Vec<u8> can never fail; qed";
 
-	let padding_len = params.padding.map_or(0, |x| x.len());
-	let padding_len_bytes = num_padding_length_bytes(padding_len)
-		.ok_or_else(|| "padding size too long")?;
-
 	let signature = params.sign_with.map(|secret| {
-		let hash = H256(keccak256(params.message));
+		let hash = signing_hash(params.message, params.sequence);
 		::ethkey::sign(secret, &hash)
 	});
 
@@ -122,6 +171,28 @@ pub fn encode(params: EncodeParams) -> Result<Vec<u8>, &'static str> {
 		None => None,
 	};
 
+	let mut padding = params.padding.map_or_else(Vec::new, |x| x.to_vec());
+	if let Some(block_size) = params.pad_to {
+		if block_size == 0 {
+			return Err("padding block size must be non-zero");
+		}
+
+		// 1 byte each for flags and version.
+		let unpadded_len = 2
+			+ signature.as_ref().map_or(0, |_| SIGNATURE_LEN)
+			+ params.sequence.map_or(0, |_| SEQUENCE_LEN)
+			+ params.message.len();
+
+		let padding_len = padding_to_block(unpadded_len, padding.len(), block_size)
+			.ok_or_else(|| "padding size too long")?;
+
+		padding.resize(padding_len, 0);
+	}
+
+	let padding_len = padding.len();
+	let padding_len_bytes = num_padding_length_bytes(padding_len)
+		.ok_or_else(|| "padding size too long")?;
+
 	let (flags, plaintext_size) = {
 		let mut flags = Flags::empty();
 
@@ -139,6 +210,11 @@ pub fn encode(params: EncodeParams) -> Result<Vec<u8>, &'static str> {
 			flags |= FLAG_SIGNED;
 		}
 
+		if params.sequence.is_some() {
+			plaintext_size += SEQUENCE_LEN;
+			flags |= FLAG_SEQUENCE;
+		}
+
 		(flags, plaintext_size)
 	};
 
@@ -147,7 +223,7 @@ pub fn encode(params: EncodeParams) -> Result<Vec<u8>, &'static str> {
 	plaintext.push(STANDARD_PAYLOAD_VERSION);
 	plaintext.push(flags.bits);
 
-	if let Some(padding) = params.padding {
+	if padding_len != 0 {
 		plaintext.write_uint::<BigEndian>(padding_len as u64, padding_len_bytes)
 			.expect(VEC_WRITE_INFALLIBLE);
 
@@ -160,6 +236,10 @@ pub fn encode(params: EncodeParams) -> Result<Vec<u8>, &'static str> {
 		plaintext.push(signature.v());
 	}
 
+	if let Some(sequence) = params.sequence {
+		plaintext.write_u64::<BigEndian>(sequence).expect(VEC_WRITE_INFALLIBLE);
+	}
+
 	plaintext.extend(params.message);
 
 	Ok(plaintext)
@@ -169,7 +249,7 @@ pub fn encode(params: EncodeParams) -> Result<Vec<u8>, &'static str> {
 pub fn decode(payload: &[u8]) -> Result<Decoded, &'static str> {
 	let mut offset = 0;
 
-	let (padding, signature) = {
+	let (padding, signature, sequence) = {
 		// use a closure for reading slices since std::io::Read would require
 		// us to copy.
 		let mut next_slice = |len| {
@@ -223,7 +303,13 @@ pub fn decode(payload: &[u8]) -> Result<Decoded, &'static str> {
 			None
 		};
 
-		(padding, signature)
+		let sequence = if flags & FLAG_SEQUENCE == FLAG_SEQUENCE {
+			Some(BigEndian::read_u64(next_slice(SEQUENCE_LEN)?))
+		} else {
+			None
+		};
+
+		(padding, signature, sequence)
 	};
 
 	// remaining data is the message.
@@ -232,7 +318,7 @@ pub fn decode(payload: &[u8]) -> Result<Decoded, &'static str> {
 	let from = match signature {
 		None => None,
 		Some(sig) => {
-			let hash = H256(keccak256(message));
+			let hash = signing_hash(message, sequence);
 			Some(::ethkey::recover(&sig, &hash).map_err(|_| "invalid signature")?)
 		}
 	};
@@ -241,6 +327,7 @@ pub fn decode(payload: &[u8]) -> Result<Decoded, &'static str> {
 		message: message,
 		padding: padding,
 		from: from,
+		sequence: sequence,
 	})
 }
 
@@ -280,6 +367,8 @@ mod tests {
 			message: &message,
 			padding: None,
 			sign_with: None,
+			pad_to: None,
+			sequence: None,
 		}).unwrap();
 
 		let decoded = decode(&encoded).unwrap();
@@ -293,6 +382,8 @@ mod tests {
 			message: &[],
 			padding: None,
 			sign_with: None,
+			pad_to: None,
+			sequence: None,
 		}).unwrap();
 
 		let decoded = decode(&encoded).unwrap();
@@ -309,6 +400,8 @@ mod tests {
 			message: &message,
 			padding: None,
 			sign_with: Some(key_pair.secret()),
+			pad_to: None,
+			sequence: None,
 		}).unwrap();
 
 		let decoded = decode(&encoded).unwrap();
@@ -327,6 +420,8 @@ mod tests {
 			message: &message,
 			padding: Some(&padding),
 			sign_with: None,
+			pad_to: None,
+			sequence: None,
 		}).unwrap();
 
 		let decoded = decode(&encoded).unwrap();
@@ -346,6 +441,8 @@ mod tests {
 			message: &message,
 			padding: Some(&padding),
 			sign_with: Some(key_pair.secret()),
+			pad_to: None,
+			sequence: None,
 		}).unwrap();
 
 		let decoded = decode(&encoded).unwrap();
@@ -354,4 +451,81 @@ mod tests {
 		assert_eq!(decoded.padding, Some(&padding[..]));
 		assert_eq!(decoded.from, Some(key_pair.public().clone()));
 	}
+
+	#[test]
+	fn padding_to_block_sanity() {
+		assert_eq!(padding_to_block(256, 0, 256), Some(0));
+		assert_eq!(padding_to_block(7, 0, 256), Some(248));
+		assert_eq!(padding_to_block(255, 0, 256), Some(511));
+		assert_eq!(padding_to_block(7, 300, 256), Some(503));
+
+		for unpadded_len in 0..1024 {
+			for &block_size in &[1, 16, 256, 1000] {
+				let padding_len = padding_to_block(unpadded_len, 0, block_size).unwrap();
+				let total = unpadded_len + num_padding_length_bytes(padding_len).unwrap() + padding_len;
+
+				assert_eq!(total % block_size, 0);
+			}
+		}
+	}
+
+	#[test]
+	fn encode_pads_to_block() {
+		let key_pair = Random.generate().unwrap();
+		let message = [1, 3, 5, 7, 9];
+
+		let encoded = encode(EncodeParams {
+			message: &message,
+			padding: None,
+			sign_with: Some(key_pair.secret()),
+			pad_to: Some(256),
+			sequence: None,
+		}).unwrap();
+
+		assert_eq!(encoded.len(), 256);
+
+		let decoded = decode(&encoded).unwrap();
+
+		assert_eq!(decoded.message, message);
+		assert_eq!(decoded.from, Some(key_pair.public().clone()));
+
+		let encoded = encode(EncodeParams {
+			message: &[0xff; 300],
+			padding: Some(&[1, 2, 3]),
+			sign_with: None,
+			pad_to: Some(256),
+			sequence: None,
+		}).unwrap();
+
+		assert_eq!(encoded.len(), 512);
+		assert_eq!(&decode(&encoded).unwrap().padding.unwrap()[..3], &[1, 2, 3]);
+	}
+
+	#[test]
+	fn encode_with_sequence() {
+		let key_pair = Random.generate().unwrap();
+		let message = [1, 3, 5, 7, 9];
+
+		let encoded = encode(EncodeParams {
+			message: &message,
+			padding: Some(&[0xff; 10]),
+			sign_with: Some(key_pair.secret()),
+			pad_to: None,
+			sequence: Some(42),
+		}).unwrap();
+
+		let decoded = decode(&encoded).unwrap();
+
+		assert_eq!(decoded.message, message);
+		assert_eq!(decoded.sequence, Some(42));
+		assert_eq!(decoded.from, Some(key_pair.public().clone()));
+
+		// the signature covers the sequence number.
+		let mut tampered = encoded.clone();
+		let seq_end = encoded.len() - message.len();
+		tampered[seq_end - 1] = 41;
+
+		let recovered = decode(&tampered).ok().and_then(|decoded| decoded.from);
+		assert!(recovered != Some(key_pair.public().clone()));
+	}
 }
@@ -84,6 +84,9 @@ pub type Bytes = HexEncode<Vec<u8>>;
 /// 32-byte local identity
 pub type Identity = HexEncode<H256>;
 
+/// 32-byte envelope ID.
+pub type EnvelopeId = HexEncode<H256>;
+
 /// Public key for ECIES, SECP256k1
 pub type Public = HexEncode<::ethkey::Public>;
 
@@ -186,6 +189,11 @@ pub struct PostRequest {
 
 	/// Time-To-Live of the message in seconds.
 	pub ttl: u64,
+
+	/// Optional sequence number. Receivers reject signed messages whose
+	/// sequence number isn't above the last seen for the same topic and
+	/// sender.
+	pub sequence: Option<u64>,
 }
 
 /// Request for filter or subscription creation.
@@ -233,6 +241,10 @@ pub struct FilterItem {
 	/// Optional padding data.
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub padding: Option<Bytes>,
+
+	/// Sequence number, if the sender attached one.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub sequence: Option<u64>,
 }
 
 /// Whisper node info.